@@ -1,4 +1,5 @@
 use crate::error::ShellError;
+use crate::parser::Token;
 
 #[derive(Debug)]
 pub struct Redirection {
@@ -15,19 +16,19 @@ impl Redirection {
     }
 }
 
-/// 分析并移除参数列表中的重定向符号
+/// 分析并移除参数列表中的重定向符号，带引号或转义的 > 和 < 不是重定向符号
 /// 返回：Redirection { output_file, input_file }
-pub fn redirection_analysis(args: &mut Vec<String>) -> Result<Redirection, ShellError> {
+pub fn redirection_analysis(args: &mut Vec<Token>) -> Result<Redirection, ShellError> {
     let mut rdr = Redirection::new();
     let mut i = 0;
 
     while i < args.len() {
-        if args[i] == ">" || args[i] == "<" {
-            let is_output = args[i] == ">";
+        if !args[i].quoted && (args[i].text == ">" || args[i].text == "<") {
+            let is_output = args[i].text == ">";
             args.remove(i);
 
             if i < args.len() {
-                let filename = args.remove(i);
+                let filename = args.remove(i).text;
 
                 if is_output {
                     rdr.output_file = Some(filename);
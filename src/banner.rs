@@ -0,0 +1,73 @@
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
+use colorgrad::Gradient;
+
+use crate::config;
+use crate::error;
+use crate::parser::parse_line;
+use crate::prompt;
+use crate::run;
+use crate::theme;
+
+const DEFAULT_BANNER: &str = r#"
+  _____      _            _____ _          _ _
+ |  __ \    | |          / ____| |        | | |
+ | |__) |_ _| |_ __ ___ | (___ | |__   ___| | |
+ |  ___/ _` | | '_ ` _ \ \___ \| '_ \ / _ \ | |
+ | |  | (_| | | | | | | |____) | | | |  __/ | |
+ |_|   \__,_|_|_| |_| |_|_____/|_| |_|\___|_|_|
+
+             Welcome to PalmShell!
+
+"#;
+
+// 启动时显示的Banner，通过配置文件或环境变量控制：
+//   PSH_BANNER=on|off|login  是否显示，login表示只在登录shell中显示，默认为on
+//   PSH_BANNER_FILE=path     用这个文件的内容代替默认的Banner，相对路径相对于配置目录
+//   PSH_MOTD=command         执行这条命令代替Banner，如 PSH_MOTD='fortune'
+// 标准输入或输出不是终端（非交互模式）时不显示
+pub fn show(login: bool) {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return;
+    }
+
+    match env::var("PSH_BANNER").unwrap_or_default().as_str() {
+        "off" | "none" | "0" | "false" | "no" => return,
+        "login" if !login => return,
+        _ => {}
+    }
+
+    if let Ok(command) = env::var("PSH_MOTD") {
+        run::handle_command(parse_line(&command), None, None);
+        return;
+    }
+
+    match env::var("PSH_BANNER_FILE") {
+        Ok(file) => {
+            let path = PathBuf::from(&file);
+            let path = if path.is_relative() { config::config_dir().join(path) } else { path };
+            match fs::read_to_string(&path) {
+                Ok(content) => print_gradient(&content),
+                Err(e) => error::report(format!("Failed to read banner '{}': {}", path.display(), e)),
+            }
+        }
+        Err(_) => print_gradient(DEFAULT_BANNER),
+    }
+}
+
+// 用主题的渐变色逐行打印
+fn print_gradient(banner: &str) {
+    let lines: Vec<&str> = banner.lines().collect();
+    let gradient = theme::with_current(|theme| theme.banner_gradient());
+    for (i, line) in lines.iter().enumerate() {
+        let t = if lines.len() > 1 { i as f32 / (lines.len() - 1) as f32 } else { 0.5 };
+        let [r, g, b, _] = gradient.at(t).to_rgba8();
+        if prompt::color_enabled() {
+            println!("\x1b[1m{}{}\x1b[0m", prompt::color_code(r, g, b), line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
@@ -1,9 +1,25 @@
 use std::env;
 use std::fs;
-use std::io::Write;
-use crate::error::ShellError;
-use crate::model_call::llm_call;
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use serde_json::json;
+use crate::chat::{self, Session};
+use crate::completion::{self, CompletionSpec};
+use crate::error::{self, ShellError};
+use crate::history;
+use crate::last_error;
+use crate::keybind;
+use crate::markdown::Renderer;
+use rustyline::config::{Behavior, EditMode};
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use crate::model_call::{self, llm_call, Params};
+use crate::parser::parse_line;
+use crate::provider;
+use crate::run;
 use crate::prompt;
+use crate::theme::{self, Theme};
+use crate::tools;
 
 pub fn builtin_cd(args: Vec<String>, _piped_input: Option<String>, _stdout: &mut dyn Write) -> Result<(), ShellError> {
     let target_dir = match args.first() {
@@ -80,17 +96,570 @@ pub fn builtin_grep(mut args: Vec<String>, piped_input: Option<String>, stdout:
     Ok(())
 }
 
-pub fn builtin_model_call(args: Vec<String>, _piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
-    if args.is_empty() {
+// chat [--new | --session NAME] [-p PROFILE] [--provider NAME] [-m MODEL] [--temperature T] [--max-tokens N]
+//      [--top-p P] [--system TEXT | --system-file FILE] [--tools] [--last-error] message...
+// chat --list
+// chat --clear [NAME]
+// chat --run [N]
+// 管道输入和< file的内容会作为上下文附加在消息后面
+pub fn builtin_model_call(args: Vec<String>, piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    let mut message = Vec::new();
+    let mut switched = false;
+    let mut explain_error = false;
+    // 选项中给出的参数优先于profile，profile优先于配置文件中的默认值
+    let mut params = Params::default();
+    let mut profile = None;
+    // 允许模型执行命令来查看环境，也可以用PSH_CHAT_TOOLS=1默认开启
+    let mut use_tools = env::var("PSH_CHAT_TOOLS").is_ok_and(|v| matches!(v.as_str(), "1" | "on" | "true" | "yes"));
+
+    let mut iter = args.into_iter().peekable();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            // 开始一个以当前时间命名的新会话
+            "--new" => {
+                chat::set_current(&chat::new_name())?;
+                writeln!(stdout, "Started session {}", chat::current())?;
+                switched = true;
+            }
+            // 切换到指定的会话，不存在时会在第一次对话后创建
+            "--session" => {
+                let name = iter.next()
+                    .ok_or_else(|| ShellError::BuiltinError("chat: --session requires a name".to_string()))?;
+                chat::set_current(&name)?;
+                switched = true;
+            }
+            // 使用配置文件中定义的一组参数（LLM_PROFILE_<NAME>_*）
+            "-p" | "--profile" => profile = Some(option_value(&mut iter, &arg)?),
+            // 这次使用的接口（openai、anthropic、ollama），默认由LLM_PROVIDER决定
+            "--provider" => params.provider = Some(option_value(&mut iter, &arg)?),
+            "-m" | "--model" => params.model = Some(option_value(&mut iter, &arg)?),
+            "--temperature" => params.temperature = Some(model_call::parse_temperature(&arg, &option_value(&mut iter, &arg)?)?),
+            "--max-tokens" => params.max_tokens = Some(model_call::parse_max_tokens(&arg, &option_value(&mut iter, &arg)?)?),
+            "--top-p" => params.top_p = Some(model_call::parse_top_p(&arg, &option_value(&mut iter, &arg)?)?),
+            "--system" => params.system = Some(option_value(&mut iter, &arg)?),
+            "--system-file" => {
+                let file = option_value(&mut iter, &arg)?;
+                params.system = Some(model_call::read_system_file(Path::new(&file))?);
+            }
+            "--tools" => use_tools = true,
+            "--no-tools" => use_tools = false,
+            // 解释上一条失败的命令
+            "--last-error" => explain_error = true,
+            "--list" => {
+                let current = chat::current();
+                for (name, count) in chat::list()? {
+                    let mark = if name == current { '*' } else { ' ' };
+                    writeln!(stdout, "{} {:<24}{} messages", mark, name, count)?;
+                }
+                return Ok(());
+            }
+            // 执行上一条回复中的代码块
+            "--run" => {
+                let index = iter.next_if(|arg| arg.parse::<usize>().is_ok())
+                    .and_then(|arg| arg.parse().ok());
+                return run_code_block(index, stdout);
+            }
+            // 清空当前（或指定的）会话的历史
+            "--clear" => {
+                let name = iter.next().unwrap_or_else(chat::current);
+                let mut session = Session::load(&name)?;
+                session.clear();
+                session.save()?;
+                writeln!(stdout, "Cleared session {}", name)?;
+                return Ok(());
+            }
+            "--" => {
+                message.extend(iter);
+                break;
+            }
+            _ => {
+                message.push(arg);
+                message.extend(iter);
+                break;
+            }
+        }
+    }
+
+    if let Some(name) = profile {
+        params = params.or(Params::profile(&name)?);
+    }
+    let provider = provider::get(params.provider.as_deref())?;
+    let input = piped_input.filter(|input| !input.trim().is_empty());
+    if explain_error {
+        let last_error = last_error::get()
+            .ok_or_else(|| ShellError::BuiltinError("chat: no command has failed yet".to_string()))?;
+        message.insert(0, chat::explain_error_message(&last_error));
+    }
+    if message.is_empty() && input.is_none() {
+        if switched {
+            return Ok(());
+        }
         return Err(ShellError::BuiltinError("chat requires a message".to_string()));
     }
 
-    writeln!(stdout, "\n{} Thinking...", prompt::get_emoji())?;
+    let message = match input {
+        Some(input) => chat::attach_input(&message.join(" "), &input)?,
+        None => message.join(" "),
+    };
+
+    // 把这次的消息加入会话，连同之前的历史一起发送
+    let mut session = Session::load(&chat::current())?;
+    session.push("user", &message);
+
+    // 状态信息输出到标准错误，不会混进管道和重定向的文件中
+    eprintln!("\n{} Thinking...", prompt::get_emoji());
+
+    // 直接输出到终端时在收到第一段回复时输出前缀，按行渲染Markdown
+    // 管道和重定向时只输出回复的原文
+    let mut renderer = run::output_is_terminal().then(Renderer::new);
+    let mut started = false;
+    let mut ends_with_newline = false;
+    let mut write_delta = |delta: &str| -> Result<(), ShellError> {
+        match renderer.as_mut() {
+            Some(renderer) => {
+                if !started {
+                    write!(stdout, "\nAI:\n")?;
+                }
+                renderer.push(delta, stdout)?;
+            }
+            None => {
+                write!(stdout, "{}", delta)?;
+                stdout.flush()?;
+            }
+        }
+        started = true;
+        if !delta.is_empty() {
+            ends_with_newline = delta.ends_with('\n');
+        }
+        Ok(())
+    };
+    let result = if use_tools {
+        let mut messages = vec![json!({ "role": "system", "content": chat::tools_system_prompt() })];
+        messages.extend(session.context());
+        tools::converse(provider.as_ref(), &params, messages, &mut write_delta)
+    } else {
+        llm_call(provider.as_ref(), &params, session.context(), &mut write_delta)
+    };
+    if started {
+        match renderer.as_mut() {
+            Some(renderer) => {
+                renderer.finish(stdout)?;
+                writeln!(stdout)?;
+            }
+            None if !ends_with_newline => writeln!(stdout)?,
+            None => {}
+        }
+    }
+    let response = result?;
+
+    // 请求成功后才保存，失败的消息不会留在会话中
+    session.push("assistant", &response);
+    session.save()?;
+
+    // 回复中建议的修复命令，确认后执行
+    if explain_error
+        && let Some(fix) = chat::extract_fix(&response)
+    {
+        if io::stdin().is_terminal() {
+            confirm_command(&fix)?;
+        } else {
+            writeln!(stdout, "{}", fix)?;
+        }
+    }
+
+    Ok(())
+}
+
+// 取出选项后面的值
+fn option_value(iter: &mut impl Iterator<Item = String>, option: &str) -> Result<String, ShellError> {
+    iter.next().ok_or_else(|| ShellError::BuiltinError(format!("chat: {} requires a value", option)))
+}
+
+// why [message...]，等同于chat --last-error
+pub fn builtin_why(args: Vec<String>, piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    let mut chat_args = vec!["--last-error".to_string()];
+    chat_args.extend(args);
+    builtin_model_call(chat_args, piped_input, stdout)
+}
+
+// 提示并读取一行回答，Ctrl+D和Ctrl+C时返回None
+// 终端上用rustyline读取，按Ctrl+C取消：请求模型时tokio安装了SIGINT的处理函数，
+// 之后Ctrl+C不会再打断read_line，只能在raw模式下作为按键读取
+pub fn read_answer(prompt: &str) -> Result<Option<String>, ShellError> {
+    if io::stdin().is_terminal() {
+        let config = rustyline::Config::builder().behavior(Behavior::PreferTerm).build();
+        let mut editor = DefaultEditor::with_config(config).map_err(|e| ShellError::BuiltinError(e.to_string()))?;
+        return match editor.readline(prompt) {
+            Ok(answer) => Ok(Some(answer.trim().to_lowercase())),
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => Ok(None),
+            Err(e) => Err(ShellError::BuiltinError(e.to_string())),
+        };
+    }
+
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        eprintln!();
+        return Ok(None);
+    }
+    Ok(Some(answer.trim().to_lowercase()))
+}
 
-    let rt = tokio::runtime::Runtime::new()?;
+// 像在提示符下输入一样执行一行命令，返回退出状态
+fn run_confirmed(command: &str) -> i32 {
+    let entry = history::push(command);
+    history::mark_changed();
+    let status = run::handle_command(parse_line(command), None, None);
+    if let Some(entry) = entry {
+        history::set_status(entry, status);
+    }
+    status
+}
 
-    let response = rt.block_on(llm_call(args.join(" ")))?;
-    writeln!(stdout, "{}", response)?;
+// 显示模型建议的命令，由用户选择执行、放到输入行中编辑或取消
+fn confirm_command(command: &str) -> Result<(), ShellError> {
+    eprintln!("\n  {}\n", command);
+    // Ctrl+D视为取消
+    while let Some(answer) = read_answer("[r]un, [e]dit or [c]ancel? ")? {
+        match answer.as_str() {
+            "r" | "run" | "y" | "yes" => {
+                run_confirmed(command);
+                return Ok(());
+            }
+            "e" | "edit" => {
+                run::set_pending_line(command);
+                return Ok(());
+            }
+            "c" | "cancel" | "n" | "no" | "" => return Ok(()),
+            _ => continue,
+        }
+    }
+    Ok(())
+}
 
+// 多行的代码块不能放到输入行中编辑，只能逐行执行，某一行失败时停止
+fn confirm_lines(lines: &[String]) -> Result<(), ShellError> {
+    eprintln!();
+    for line in lines {
+        eprintln!("  {}", line);
+    }
+    eprintln!();
+    while let Some(answer) = read_answer("[r]un all or [c]ancel? ")? {
+        match answer.as_str() {
+            "r" | "run" | "y" | "yes" => {
+                for line in lines {
+                    let status = run_confirmed(line);
+                    if status != 0 {
+                        error::report(format!("stopped: '{}' exited with status {}", line, status));
+                        break;
+                    }
+                }
+                return Ok(());
+            }
+            "c" | "cancel" | "n" | "no" | "" => return Ok(()),
+            _ => continue,
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+// chat --run [N]：执行当前会话最后一条回复中的第N个sh/bash代码块
+// 没有给出N时列出所有代码块并让用户选择；标准输入不是终端时只输出代码块，不会执行
+fn run_code_block(index: Option<usize>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    let session = Session::load(&chat::current())?;
+    let reply = session.last_reply()
+        .ok_or_else(|| ShellError::BuiltinError(format!("chat: session {} has no answer yet", session.name)))?;
+    let blocks = chat::shell_blocks(reply);
+    if blocks.is_empty() {
+        return Err(ShellError::BuiltinError("chat: the last answer has no sh/bash code blocks".to_string()));
+    }
+
+    let interactive = io::stdin().is_terminal();
+    let index = match index {
+        Some(index) => index,
+        None => {
+            for (i, block) in blocks.iter().enumerate() {
+                for (j, line) in block.iter().enumerate() {
+                    let label = if j == 0 { format!("[{}]", i + 1) } else { String::new() };
+                    writeln!(stdout, "{:<4} {}", label, line)?;
+                }
+            }
+            if !interactive {
+                return Ok(());
+            }
+            if blocks.len() == 1 {
+                1
+            } else {
+                let Some(answer) = read_answer(&format!("Run which block? [1-{}] ", blocks.len()))? else {
+                    return Ok(());
+                };
+                if answer.is_empty() {
+                    return Ok(());
+                }
+                answer.parse().map_err(|_| ShellError::BuiltinError(format!("chat: invalid block number '{}'", answer)))?
+            }
+        }
+    };
+
+    let block = index.checked_sub(1).and_then(|i| blocks.get(i)).ok_or_else(|| {
+        ShellError::BuiltinError(format!("chat: no code block {} (the last answer has {})", index, blocks.len()))
+    })?;
+    if !interactive {
+        for line in block {
+            writeln!(stdout, "{}", line)?;
+        }
+        return Ok(());
+    }
+
+    match block.as_slice() {
+        [command] => confirm_command(command),
+        lines => confirm_lines(lines),
+    }
+}
+
+// ask 自然语言描述
+// 让模型把描述翻译成一条命令，确认后才会执行；也可以放到输入行中编辑后再执行
+// 标准输入不是终端时只输出命令，不会执行
+pub fn builtin_ask(args: Vec<String>, _piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    if args.is_empty() {
+        return Err(ShellError::BuiltinError("ask requires a description".to_string()));
+    }
+
+    let interactive = io::stdin().is_terminal();
+    if interactive {
+        eprintln!("{} Thinking...", prompt::get_emoji());
+    }
+
+    let messages = vec![
+        json!({ "role": "system", "content": chat::command_system_prompt() }),
+        json!({ "role": "user", "content": args.join(" ") }),
+    ];
+    let provider = provider::get(None)?;
+    let reply = llm_call(provider.as_ref(), &Params::default(), messages, &mut |_| Ok(()))?;
+    let command = chat::extract_command(&reply)
+        .ok_or_else(|| ShellError::LLMError("the model did not suggest a command".to_string()))?;
+
+    if !interactive {
+        writeln!(stdout, "{}", command)?;
+        return Ok(());
+    }
+
+    confirm_command(&command)
+}
+
+pub fn builtin_history(args: Vec<String>, _piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    let entries = history::entries();
+
+    match args.first().map(String::as_str) {
+        // 列出全部历史
+        None => {
+            for (i, entry) in entries.iter().enumerate() {
+                writeln!(stdout, "{:>5}  {}", i + 1, entry.line)?;
+            }
+        }
+        // 搜索包含pattern的历史
+        Some("-s") => {
+            if args.len() < 2 {
+                return Err(ShellError::BuiltinError("history -s requires a pattern".to_string()));
+            }
+            let pattern = args[1..].join(" ");
+            for (i, entry) in entries.iter().enumerate() {
+                if entry.line.contains(&pattern) {
+                    writeln!(stdout, "{:>5}  {}", i + 1, entry.line)?;
+                }
+            }
+        }
+        // 清空历史
+        Some("-c") => history::clear(),
+        // 删除指定编号的历史
+        Some("-d") => {
+            let n = args.get(1)
+                .and_then(|n| n.parse::<usize>().ok())
+                .ok_or_else(|| ShellError::BuiltinError("history -d requires a position".to_string()))?;
+            history::delete(n)?;
+        }
+        // 只列出最近n条
+        Some(n) => {
+            let n: usize = n.parse()
+                .map_err(|_| ShellError::BuiltinError(format!("history: {}: numeric argument required", n)))?;
+            let skip = entries.len().saturating_sub(n);
+            for (i, entry) in entries.iter().enumerate().skip(skip) {
+                writeln!(stdout, "{:>5}  {}", i + 1, entry.line)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// complete [-W words] [-f] [-d] [-C command] name...
+// complete -r name...
+// complete [-p]
+pub fn builtin_complete(args: Vec<String>, _piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    let mut spec = CompletionSpec::default();
+    let mut remove = false;
+    let mut names = Vec::new();
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-W" => {
+                let words = iter.next()
+                    .ok_or_else(|| ShellError::BuiltinError("complete: -W requires a word list".to_string()))?;
+                spec.words.extend(words.split_whitespace().map(String::from));
+            }
+            "-C" => {
+                spec.command = Some(iter.next()
+                    .ok_or_else(|| ShellError::BuiltinError("complete: -C requires a command".to_string()))?);
+            }
+            "-f" => spec.files = true,
+            "-d" => spec.dirs = true,
+            "-r" => remove = true,
+            "-p" => {}
+            _ if arg.starts_with('-') => {
+                return Err(ShellError::BuiltinError(format!("complete: {}: invalid option", arg)));
+            }
+            _ => names.push(arg),
+        }
+    }
+
+    // 没有给出命令名时列出所有补全规则
+    if names.is_empty() {
+        for (name, spec) in completion::specs() {
+            let mut line = String::from("complete");
+            if !spec.words.is_empty() {
+                line.push_str(&format!(" -W '{}'", spec.words.join(" ")));
+            }
+            if spec.files {
+                line.push_str(" -f");
+            }
+            if spec.dirs {
+                line.push_str(" -d");
+            }
+            if let Some(cmd) = &spec.command {
+                line.push_str(&format!(" -C '{}'", cmd));
+            }
+            writeln!(stdout, "{} '{}'", line, name)?;
+        }
+        return Ok(());
+    }
+
+    for name in names {
+        if remove {
+            if !completion::remove_spec(&name) {
+                return Err(ShellError::BuiltinError(format!("complete: {}: no completion specification", name)));
+            }
+        } else {
+            completion::set_spec(&name, spec.clone());
+        }
+    }
+
+    Ok(())
+}
+
+// set -o vi / set -o emacs 切换编辑模式，set -o 列出当前模式
+pub fn builtin_set(args: Vec<String>, _piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    match (args.first().map(String::as_str), args.get(1).map(String::as_str)) {
+        (None, _) | (Some("-o"), None) => {
+            let mode = keybind::mode();
+            writeln!(stdout, "emacs\t{}", if mode == EditMode::Emacs { "on" } else { "off" })?;
+            writeln!(stdout, "vi\t{}", if mode == EditMode::Vi { "on" } else { "off" })?;
+        }
+        (Some("-o"), Some("vi")) | (Some("+o"), Some("emacs")) => keybind::set_mode(EditMode::Vi),
+        (Some("-o"), Some("emacs")) | (Some("+o"), Some("vi")) => keybind::set_mode(EditMode::Emacs),
+        (Some(_), Some(option)) => {
+            return Err(ShellError::BuiltinError(format!("set: {}: invalid option name", option)));
+        }
+        (Some(flag), None) => {
+            return Err(ShellError::BuiltinError(format!("set: {}: invalid option", flag)));
+        }
+    }
+
+    Ok(())
+}
+
+// bind KEY ACTION 绑定按键，bind -r KEY 解除绑定
+// bind -l 列出可用的动作，bind / bind -p 列出当前绑定
+pub fn builtin_bind(args: Vec<String>, _piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    match args.first().map(String::as_str) {
+        None | Some("-p") => {
+            for (key, action) in keybind::bindings() {
+                writeln!(stdout, "{}: {}", key, action)?;
+            }
+        }
+        Some("-l") => {
+            for (action, description) in keybind::ACTIONS {
+                writeln!(stdout, "{:<24}{}", action, description)?;
+            }
+        }
+        Some("-r") => {
+            let key = args.get(1)
+                .ok_or_else(|| ShellError::BuiltinError("bind -r requires a key".to_string()))?;
+            keybind::unbind(key)?;
+        }
+        Some(key) => {
+            let action = args.get(1)
+                .ok_or_else(|| ShellError::BuiltinError("bind requires a key and an action".to_string()))?;
+            keybind::bind(key, action)?;
+        }
+    }
+
+    Ok(())
+}
+
+// theme 列出主题，theme NAME 切换主题，theme -p [NAME] 预览主题
+pub fn builtin_theme(args: Vec<String>, _piped_input: Option<String>, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    match args.first().map(String::as_str) {
+        None => {
+            let current = theme::with_current(|theme| theme.name.clone());
+            for name in theme::names() {
+                let marker = if name == current { "*" } else { " " };
+                writeln!(stdout, "{} {}", marker, name)?;
+            }
+        }
+        Some("-p") | Some("--preview") => {
+            let theme = match args.get(1) {
+                Some(name) => theme::load(name)?,
+                None => theme::with_current(Theme::clone),
+            };
+            preview_theme(&theme, stdout)?;
+        }
+        Some(name) => theme::set_current(theme::load(name)?),
+    }
+
+    Ok(())
+}
+
+fn preview_theme(theme: &Theme, stdout: &mut dyn Write) -> Result<(), ShellError> {
+    writeln!(stdout, "Theme: {}", theme.name)?;
+
+    // 输出到管道、重定向或者关闭了颜色时不输出转义序列
+    let colored = prompt::detect_color_level(run::output_is_terminal()) != prompt::ColorLevel::None;
+    let gradient = |text: &str, gradient: &dyn colorgrad::Gradient| {
+        if colored { prompt::gradient_text(text, gradient, false) } else { text.to_string() }
+    };
+
+    // 提示符各部分
+    let samples = ["PalmShell", "palm", "localhost", "~/projects/psh", "18/10/2026 14:05", "✘ 127", "2", "main ↑1 +!", "took 12.3s"];
+    for (segment, sample) in theme::PROMPT_SEGMENTS.iter().zip(samples) {
+        writeln!(stdout, "  {:<12}{}", segment, gradient(sample, &theme.gradient(segment)))?;
+    }
+
+    // Banner
+    writeln!(stdout, "  {:<12}{}", "banner", gradient(&"█".repeat(24), &theme.banner_gradient()))?;
+
+    // 语法高亮
+    for element in theme::HIGHLIGHT_ELEMENTS {
+        let (r, g, b) = theme.highlight_color(element);
+        let code = if colored { prompt::color_code(r, g, b) } else { String::new() };
+        if code.is_empty() {
+            writeln!(stdout, "  {:<12}{}", element, element)?;
+        } else {
+            writeln!(stdout, "  {:<12}{}{}\x1b[0m", element, code, element)?;
+        }
+    }
+
+    Ok(())
+}
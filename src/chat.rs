@@ -0,0 +1,407 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use serde_json::{json, Value};
+
+use crate::config;
+use crate::error::{self, ShellError};
+use crate::last_error::LastError;
+use crate::parser::BUILTINS;
+
+// 发送给模型的历史消息的默认预算（估算的token数），可以通过PSH_CHAT_CONTEXT修改
+const DEFAULT_CONTEXT_BUDGET: usize = 8000;
+const DEFAULT_SESSION: &str = "default";
+// 管道或重定向输入的默认字符数上限，可以通过PSH_CHAT_INPUT_LIMIT修改
+const DEFAULT_INPUT_LIMIT: usize = 32000;
+
+// 当前使用的会话，每个psh进程单独记录，启动时为default
+static CURRENT: LazyLock<Mutex<String>> = LazyLock::new(|| Mutex::new(DEFAULT_SESSION.to_string()));
+
+// 保存在磁盘上的一个对话，每个会话一个JSON文件：
+// { "name": "default", "messages": [ { "role": "user", "content": "..." }, ... ] }
+pub struct Session {
+    pub name: String,
+    pub messages: Vec<Value>,
+}
+
+// 会话文件所在的目录
+pub fn sessions_dir() -> PathBuf {
+    config::data_dir().join("chats")
+}
+
+fn session_path(name: &str) -> PathBuf {
+    sessions_dir().join(format!("{}.json", name))
+}
+
+fn check_name(name: &str) -> Result<(), ShellError> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(ShellError::BuiltinError(format!("chat: invalid session name '{}'", name)));
+    }
+    Ok(())
+}
+
+pub fn current() -> String {
+    CURRENT.lock().unwrap().clone()
+}
+
+pub fn set_current(name: &str) -> Result<(), ShellError> {
+    check_name(name)?;
+    *CURRENT.lock().unwrap() = name.to_string();
+    Ok(())
+}
+
+// 用当前时间为新会话命名
+pub fn new_name() -> String {
+    chrono::Local::now().format("%Y%m%d-%H%M%S").to_string()
+}
+
+// 所有会话的名字和消息数，按名字排序
+pub fn list() -> Result<Vec<(String, usize)>, ShellError> {
+    let mut sessions = Vec::new();
+    let Ok(entries) = fs::read_dir(sessions_dir()) else {
+        return Ok(sessions);
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Some(name) = path.file_stem().and_then(|s| s.to_str()) else { continue };
+        // 损坏的会话文件不影响列出其他会话
+        match Session::load(name) {
+            Ok(session) => sessions.push((session.name, session.messages.len())),
+            Err(e) => error::report(format!("{} (skipped)", e)),
+        }
+    }
+    sessions.sort();
+
+    Ok(sessions)
+}
+
+// 向模型描述psh和当前环境，作为system prompt的一部分
+pub fn shell_description() -> String {
+    let cwd = env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_default();
+    format!(
+        "The user is working in psh (PalmShell), a small interactive shell.\n\
+         Current directory: {}\n\
+         Operating system: {} ({})\n\
+         User: {}\n\
+         psh builtins: {}\n\
+         Other programs are run from PATH. psh supports pipes (|), background jobs (&), \
+         redirection (< file, > file), quotes and backslash escapes. \
+         It does NOT support variable expansion, globbing, &&, ||, ; or subshells.",
+        cwd,
+        env::consts::OS,
+        env::consts::ARCH,
+        whoami::username(),
+        BUILTINS.join(", "),
+    )
+}
+
+// ask使用的system prompt：只要求返回一条命令
+pub fn command_system_prompt() -> String {
+    format!(
+        "{}\n\nTranslate the user's request into a single psh command line. \
+         Reply with the command only: no explanation, no markdown, no code fences.",
+        shell_description()
+    )
+}
+
+// chat --tools使用的system prompt：说明可以用run_command查看用户的环境
+pub fn tools_system_prompt() -> String {
+    format!(
+        "{}\n\nYou can investigate the user's environment with the run_command tool before answering. \
+         Prefer read-only commands; commands that are not read-only need the user's approval and may be declined.",
+        shell_description()
+    )
+}
+
+// 从模型的回复中取出命令：去掉代码块标记，取第一行非空内容
+pub fn extract_command(reply: &str) -> Option<String> {
+    reply.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("```"))
+        .map(|line| line.trim_matches('`').trim_start_matches("$ ").to_string())
+        .next()
+        .filter(|line| !line.is_empty())
+}
+
+// 请模型解释失败的命令，并把修复命令放在最后的代码块中
+pub fn explain_error_message(error: &LastError) -> String {
+    let stderr = if error.stderr.trim().is_empty() {
+        "(no stderr captured; set PSH_CAPTURE_STDERR=1 to capture it)".to_string()
+    } else {
+        error.stderr.trim_end().to_string()
+    };
+    format!(
+        "{}\n\nThis command failed:\n{}\nExit status: {}\nEnd of its stderr:\n<stderr>\n{}\n</stderr>\n\n\
+         Briefly explain what went wrong. If a psh command would fix it, \
+         end your answer with exactly one such command in a ```sh code block.",
+        shell_description(), error.line, error.status, stderr,
+    )
+}
+
+// 取出回复中最后一个代码块的第一行作为建议的修复命令
+pub fn extract_fix(reply: &str) -> Option<String> {
+    // 按```分割后，奇数下标的部分是代码块的内容，最后一部分不是闭合的代码块
+    let parts: Vec<&str> = reply.split("```").collect();
+    let block = (1..parts.len().saturating_sub(1)).step_by(2).map(|i| parts[i]).next_back()?;
+    // 第一行是语言标记（如sh）
+    let code = block.split_once('\n').map_or(block, |(_, code)| code);
+    extract_command(code)
+}
+
+// 取出回复中所有shell代码块，每个代码块是若干行命令，忽略空行和注释，去掉开头的$提示符
+pub fn shell_blocks(reply: &str) -> Vec<Vec<String>> {
+    const SHELL_LANGS: &[&str] = &["sh", "bash", "shell", "zsh", "console", "psh"];
+
+    let mut blocks = Vec::new();
+    // 当前代码块的开始标记、是否是shell代码和已经收集的行
+    let mut current: Option<(&str, bool, Vec<String>)> = None;
+    for line in reply.lines() {
+        let trimmed = line.trim();
+        match current.as_mut() {
+            Some((fence, is_shell, lines)) => {
+                if trimmed == *fence {
+                    if *is_shell && !lines.is_empty() {
+                        blocks.push(std::mem::take(lines));
+                    }
+                    current = None;
+                } else if *is_shell && !trimmed.is_empty() && !trimmed.starts_with('#') {
+                    lines.push(trimmed.strip_prefix("$ ").unwrap_or(trimmed).to_string());
+                }
+            }
+            None => {
+                for fence in ["```", "~~~"] {
+                    if let Some(lang) = trimmed.strip_prefix(fence) {
+                        let lang = lang.split_whitespace().next().unwrap_or_default().to_lowercase();
+                        current = Some((fence, SHELL_LANGS.contains(&lang.as_str()), Vec::new()));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    blocks
+}
+
+// 输入是否像二进制数据：含有NUL，或者开头部分有较多无法按UTF-8解码的字节（读入时已替换为U+FFFD）
+fn looks_binary(input: &str) -> bool {
+    let sample: Vec<char> = input.chars().take(8192).collect();
+    let invalid = sample.iter().filter(|&&c| c == '\u{FFFD}').count();
+    sample.contains(&'\0') || invalid * 10 > sample.len()
+}
+
+// 把管道或< file的内容作为上下文附加到消息后面
+// 超过上限时保留开头和结尾，中间替换为省略说明，并提示用户
+pub fn attach_input(message: &str, input: &str) -> Result<String, ShellError> {
+    if looks_binary(input) {
+        return Err(ShellError::BuiltinError("chat: input looks like binary data, refusing to send it".to_string()));
+    }
+
+    let limit = env::var("PSH_CHAT_INPUT_LIMIT").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_INPUT_LIMIT);
+    let count = input.chars().count();
+    if count > limit {
+        error::report(format!("chat: input truncated to {} of {} characters", limit, count));
+    }
+    let content = truncate_middle(input, limit);
+
+    let block = format!("<input>\n{}\n</input>", content.trim_end_matches('\n'));
+    Ok(if message.is_empty() {
+        block
+    } else {
+        format!("{}\n\n{}", message, block)
+    })
+}
+
+// 超过limit个字符时只保留开头和结尾，中间注明省略的字符数
+pub fn truncate_middle(text: &str, limit: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.len() <= limit {
+        return text.to_string();
+    }
+    let head: String = chars[..limit / 2].iter().collect();
+    let tail: String = chars[chars.len() - (limit - limit / 2)..].iter().collect();
+    format!("{}\n[... {} characters omitted ...]\n{}", head, chars.len() - limit, tail)
+}
+
+// 粗略估算一条消息的token数：大约4个字符一个token，再加上每条消息的固定开销
+fn estimate_tokens(message: &Value) -> usize {
+    message["content"].as_str().map_or(0, |content| content.chars().count() / 4) + 4
+}
+
+impl Session {
+    // 读取会话，文件不存在时返回一个空会话
+    pub fn load(name: &str) -> Result<Session, ShellError> {
+        check_name(name)?;
+        let path = session_path(name);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Session { name: name.to_string(), messages: Vec::new() });
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let json: Value = serde_json::from_str(&content)
+            .map_err(|e| ShellError::BuiltinError(format!("chat: '{}' is not a valid session file: {}", path.display(), e)))?;
+        Ok(Session {
+            name: name.to_string(),
+            messages: json["messages"].as_array().cloned().unwrap_or_default(),
+        })
+    }
+
+    pub fn save(&self) -> Result<(), ShellError> {
+        fs::create_dir_all(sessions_dir())?;
+        let json = json!({ "name": self.name, "messages": self.messages });
+        let content = serde_json::to_string_pretty(&json)
+            .map_err(|e| ShellError::BuiltinError(format!("chat: failed to save session: {}", e)))?;
+        fs::write(session_path(&self.name), content)?;
+        Ok(())
+    }
+
+    pub fn push(&mut self, role: &str, content: &str) {
+        self.messages.push(json!({ "role": role, "content": content }));
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    // 最后一条模型回复
+    pub fn last_reply(&self) -> Option<&str> {
+        self.messages.iter()
+            .rev()
+            .find(|message| message["role"] == "assistant")
+            .and_then(|message| message["content"].as_str())
+    }
+
+    // 发送给模型的消息：从最新的消息往前取，直到超出预算
+    // 最新的一条消息总是会被发送
+    pub fn context(&self) -> Vec<Value> {
+        let budget = env::var("PSH_CHAT_CONTEXT").ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CONTEXT_BUDGET);
+        self.context_within(budget)
+    }
+
+    fn context_within(&self, budget: usize) -> Vec<Value> {
+        let mut used = 0;
+        let mut start = self.messages.len();
+        for (i, message) in self.messages.iter().enumerate().rev() {
+            let tokens = estimate_tokens(message);
+            if used + tokens > budget && start < self.messages.len() {
+                break;
+            }
+            used += tokens;
+            start = i;
+        }
+
+        self.messages[start..].to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(contents: &[&str]) -> Session {
+        let mut session = Session { name: "test".to_string(), messages: Vec::new() };
+        for (i, content) in contents.iter().enumerate() {
+            session.push(if i % 2 == 0 { "user" } else { "assistant" }, content);
+        }
+        session
+    }
+
+    fn contents(messages: &[Value]) -> Vec<&str> {
+        messages.iter().map(|message| message["content"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn trims_context_to_budget() {
+        // 每条消息估算为 40/4 + 4 = 14 个token
+        let text = "x".repeat(40);
+        let session = session(&[&format!("1{}", &text[1..]), &format!("2{}", &text[1..]), &format!("3{}", &text[1..])]);
+        assert_eq!(session.context_within(100).len(), 3);
+        assert_eq!(session.context_within(42).len(), 3);
+        let recent = session.context_within(41);
+        assert_eq!(recent.len(), 2);
+        assert!(contents(&recent)[0].starts_with('2'));
+        assert_eq!(session.context_within(14).len(), 1);
+    }
+
+    #[test]
+    fn always_sends_latest_message() {
+        let session = session(&["short", &"y".repeat(1000)]);
+        let context = session.context_within(10);
+        assert_eq!(context.len(), 1);
+        assert!(contents(&context)[0].starts_with('y'));
+        assert!(Session { name: "empty".to_string(), messages: Vec::new() }.context_within(10).is_empty());
+    }
+
+    #[test]
+    fn picks_shell_blocks() {
+        let reply = "Try this:\n```bash\n$ ls -la\n# list files\n\ncat a.txt\n```\n\
+                     Code:\n```python\nprint(1)\n```\n```\nuntagged\n```\n~~~sh\necho ```\n~~~\n```sh\n```\n";
+        assert_eq!(shell_blocks(reply), [vec!["ls -la", "cat a.txt"], vec!["echo ```"]]);
+        assert!(shell_blocks("No code here.").is_empty());
+        // 没有闭合的代码块不算
+        assert!(shell_blocks("```sh\nrm -rf build").is_empty());
+        assert_eq!(shell_blocks("```Shell title\npwd\n```"), [vec!["pwd"]]);
+    }
+
+    #[test]
+    fn extracts_commands() {
+        assert_eq!(extract_command("ls -la").as_deref(), Some("ls -la"));
+        assert_eq!(extract_command("```sh\n$ git status\n```").as_deref(), Some("git status"));
+        assert_eq!(extract_command("\n`pwd`\n").as_deref(), Some("pwd"));
+        assert_eq!(extract_command("```\n```"), None);
+        assert_eq!(extract_command(""), None);
+    }
+
+    #[test]
+    fn extracts_fix_from_last_block() {
+        let reply = "It failed.\n```text\nerror: x\n```\nRun:\n```sh\nmkdir -p out\n```\n";
+        assert_eq!(extract_fix(reply).as_deref(), Some("mkdir -p out"));
+        assert_eq!(extract_fix("```\nls\n```").as_deref(), Some("ls"));
+        assert_eq!(extract_fix("No fix needed."), None);
+        // 没有闭合的代码块不算
+        assert_eq!(extract_fix("```sh\nls\n```\nthen\n```sh\nrm x").as_deref(), Some("ls"));
+    }
+
+    #[test]
+    fn truncates_the_middle() {
+        assert_eq!(truncate_middle("abcdef", 6), "abcdef");
+        assert_eq!(truncate_middle("abcdefg", 6), "abc\n[... 1 characters omitted ...]\nefg");
+        assert_eq!(truncate_middle("abcdefg", 5), "ab\n[... 2 characters omitted ...]\nefg");
+        assert_eq!(truncate_middle("abc", 0), "\n[... 3 characters omitted ...]\n");
+        // 按字符而不是字节截断，不会切开多字节字符
+        assert_eq!(truncate_middle("你好世界🌍é", 6), "你好世界🌍é");
+        assert_eq!(truncate_middle("你好世界🌍é", 4), "你好\n[... 2 characters omitted ...]\n🌍é");
+        assert_eq!(truncate_middle("é🌍é", 1), "\n[... 2 characters omitted ...]\né");
+    }
+
+    #[test]
+    fn detects_binary_input() {
+        assert!(!looks_binary("plain text\nwith lines\n"));
+        assert!(!looks_binary("中文和emoji 🌍"));
+        assert!(!looks_binary(""));
+        assert!(looks_binary("ELF\0\0\0"));
+        // 少量无法解码的字节不算二进制
+        let text = format!("{}\u{FFFD}", "a".repeat(20));
+        assert!(!looks_binary(&text));
+        let garbage = format!("ab{}", "\u{FFFD}".repeat(5));
+        assert!(looks_binary(&garbage));
+    }
+
+    #[test]
+    fn attaches_input() {
+        assert_eq!(attach_input("explain", "line 1\nline 2\n\n").unwrap(), "explain\n\n<input>\nline 1\nline 2\n</input>");
+        assert_eq!(attach_input("", "data").unwrap(), "<input>\ndata\n</input>");
+        assert!(attach_input("explain", "\0\x01binary").is_err());
+    }
+}
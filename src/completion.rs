@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+use rustyline::completion::{Completer, Pair};
+use rustyline::validate::Validator;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper};
+
+use crate::highlight::RightPrompt;
+use crate::parser::{tokenize, Token, TokenKind, BUILTINS};
+use crate::run;
+
+// 带有psh Helper的Editor
+pub type PshEditor = Editor<PshHelper, DefaultHistory>;
+
+// 通过complete命令为某个命令注册的补全规则
+// 键可以是命令名，也可以是"命令 子命令"，如"cargo build"
+#[derive(Debug, Clone, Default)]
+pub struct CompletionSpec {
+    // -W 固定的候选词
+    pub words: Vec<String>,
+    // -f 补全文件（和目录）
+    pub files: bool,
+    // -d 只补全目录
+    pub dirs: bool,
+    // -C 执行这条psh命令，每行输出作为一个候选词
+    pub command: Option<String>,
+}
+
+static SPECS: LazyLock<Mutex<BTreeMap<String, CompletionSpec>>> = LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+pub fn set_spec(name: &str, spec: CompletionSpec) {
+    SPECS.lock().unwrap().insert(name.to_string(), spec);
+}
+
+pub fn remove_spec(name: &str) -> bool {
+    SPECS.lock().unwrap().remove(name).is_some()
+}
+
+pub fn specs() -> Vec<(String, CompletionSpec)> {
+    SPECS.lock().unwrap().iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+// 根据当前命令已输入的单词查找补全规则，优先匹配最长的"命令 子命令"
+fn find_spec(words: &[&str]) -> Option<CompletionSpec> {
+    let specs = SPECS.lock().unwrap();
+    (1..=words.len()).rev()
+        .find_map(|n| specs.get(&words[..n].join(" ")).cloned())
+}
+
+// 按照补全规则生成候选
+// 执行-C命令时，在命令后追加 命令名 当前单词 前一个单词 三个参数（与bash一致）
+fn complete_with_spec(spec: &CompletionSpec, command: &[&str], word: &str, open_quote: Option<char>) -> Vec<Pair> {
+    let mut candidates: Vec<Pair> = spec.words.iter()
+        .filter(|w| w.starts_with(word))
+        .map(|w| Pair { display: w.clone(), replacement: format!("{} ", escape(w)) })
+        .collect();
+
+    if let Some(cmd) = &spec.command {
+        let previous = command.last().copied().unwrap_or("");
+        let line = format!("{} {} {} {}", cmd, quote(command[0]), quote(word), quote(previous));
+        if let Ok(output) = run::capture_output(&line) {
+            candidates.extend(output.lines()
+                .map(str::trim)
+                .filter(|w| !w.is_empty() && w.starts_with(word))
+                .map(|w| Pair { display: w.to_string(), replacement: format!("{} ", escape(w)) }));
+        }
+    }
+
+    if spec.files || spec.dirs {
+        candidates.extend(complete_path(word, open_quote, spec.dirs));
+    }
+
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    candidates.dedup_by(|a, b| a.display == b.display);
+    candidates
+}
+
+// rustyline的Helper，负责Tab补全，语法高亮和自动建议分别在highlight.rs和hint.rs中实现
+#[derive(Default)]
+pub struct PshHelper {
+    // 缓存PATH中的可执行文件，PATH变化时重新扫描
+    // (PATH的值, 可执行文件名列表)
+    path_cache: RefCell<Option<(String, Vec<String>)>>,
+    // 当前显示的右侧提示符，由main_loop在每次读取输入前设置
+    right_prompt: RefCell<Option<RightPrompt>>,
+}
+
+impl PshHelper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_right_prompt(&self, right_prompt: Option<RightPrompt>) {
+        *self.right_prompt.borrow_mut() = right_prompt;
+    }
+
+    pub fn right_prompt(&self) -> std::cell::Ref<'_, Option<RightPrompt>> {
+        self.right_prompt.borrow()
+    }
+
+    // 用PATH中的所有可执行文件名（已排序去重）调用f
+    fn with_path_executables<R>(&self, f: impl FnOnce(&[String]) -> R) -> R {
+        let path_var = env::var("PATH").unwrap_or_default();
+
+        let mut cache = self.path_cache.borrow_mut();
+        if let Some((cached_path, executables)) = cache.as_ref()
+            && *cached_path == path_var
+        {
+            return f(executables);
+        }
+
+        let mut executables = Vec::new();
+        for dir in env::split_paths(&path_var) {
+            let Ok(entries) = fs::read_dir(&dir) else { continue };
+            for entry in entries.flatten() {
+                let is_executable = entry.metadata()
+                    .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0);
+                if is_executable && let Some(name) = entry.file_name().to_str() {
+                    executables.push(name.to_string());
+                }
+            }
+        }
+        executables.sort();
+        executables.dedup();
+
+        let result = f(&executables);
+        *cache = Some((path_var, executables));
+        result
+    }
+
+    // PATH中是否存在这个可执行文件
+    pub fn is_executable(&self, name: &str) -> bool {
+        self.with_path_executables(|executables| executables.binary_search_by(|e| e.as_str().cmp(name)).is_ok())
+    }
+
+    // 补全命令名：内建命令和PATH中的可执行文件
+    fn complete_command(&self, prefix: &str) -> Vec<Pair> {
+        let mut names: Vec<String> = BUILTINS.iter()
+            .chain(["exit", "quit"].iter())
+            .map(|s| s.to_string())
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        self.with_path_executables(|executables| {
+            names.extend(executables.iter().filter(|name| name.starts_with(prefix)).cloned());
+        });
+        names.sort();
+        names.dedup();
+
+        names.into_iter()
+            .map(|name| Pair { replacement: format!("{} ", escape(&name)), display: name })
+            .collect()
+    }
+}
+
+// 补全环境变量名，prefix不含$
+fn complete_variable(prefix: &str) -> Vec<Pair> {
+    let mut names: Vec<String> = env::vars()
+        .map(|(name, _)| name)
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    names.sort();
+
+    names.into_iter()
+        .map(|name| Pair { replacement: format!("${}", name), display: format!("${}", name) })
+        .collect()
+}
+
+// 补全文件路径
+// word是去掉引号后的内容，open_quote是未闭合的引号，补全结果会保留这个引号
+// dirs_only为true时只补全目录
+fn complete_path(word: &str, open_quote: Option<char>, dirs_only: bool) -> Vec<Pair> {
+    // 拆分出目录部分和文件名前缀，目录部分保持用户输入的样子
+    let (dir_part, file_prefix) = match word.rfind('/') {
+        Some(idx) => (&word[..=idx], &word[idx + 1..]),
+        None => ("", word),
+    };
+
+    // 展开~，实际读取的目录
+    let search_dir = if dir_part.is_empty() {
+        ".".to_string()
+    } else if let Some(rest) = dir_part.strip_prefix("~/") {
+        let home = env::var("HOME").unwrap_or_default();
+        format!("{}/{}", home, rest)
+    } else {
+        dir_part.to_string()
+    };
+
+    let Ok(entries) = fs::read_dir(Path::new(&search_dir)) else {
+        return Vec::new();
+    };
+
+    let mut candidates = Vec::new();
+    for entry in entries.flatten() {
+        let Some(name) = entry.file_name().to_str().map(String::from) else { continue };
+        // 除非显式输入了.，否则不补全隐藏文件
+        if !name.starts_with(file_prefix) || (name.starts_with('.') && !file_prefix.starts_with('.')) {
+            continue;
+        }
+
+        let is_dir = entry.path().is_dir();
+        if dirs_only && !is_dir {
+            continue;
+        }
+        let path = format!("{}{}", dir_part, name);
+        let replacement = match open_quote {
+            // 在引号内补全：目录保持引号未闭合以便继续补全，文件则闭合引号
+            Some(q) if is_dir => format!("{}{}/", q, path),
+            Some(q) => format!("{}{}{} ", q, path, q),
+            None if is_dir => format!("{}/", escape(&path)),
+            None => format!("{} ", escape(&path)),
+        };
+        let display = if is_dir { format!("{}/", name) } else { name };
+
+        candidates.push(Pair { display, replacement });
+    }
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+
+    candidates
+}
+
+// 用反斜杠转义parser会特殊处理的字符
+fn escape(word: &str) -> String {
+    let mut escaped = String::new();
+    for ch in word.chars() {
+        if ch.is_whitespace() || "'\"\\|&".contains(ch) {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+// 用单引号包裹一个参数，供拼接命令行使用
+fn quote(word: &str) -> String {
+    if word.contains('\'') {
+        format!("\"{}\"", word)
+    } else {
+        format!("'{}'", word)
+    }
+}
+
+impl Completer for PshHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        let tokens = tokenize(line);
+
+        // 光标紧跟在一个单词后面时补全这个单词，否则从光标处开始一个新单词
+        let (current, previous) = match tokens.split_last() {
+            Some((last, rest)) if last.kind == TokenKind::Word && last.end == pos => (Some(last), rest),
+            _ => (None, tokens.as_slice()),
+        };
+        let start = current.map_or(pos, |t| t.start);
+        let word = current.map_or("", |t| t.text.as_str());
+        let open_quote = current.and_then(|t| t.open_quote);
+
+        // 当前单词是否处于命令名的位置（行首或管道、&之后）
+        let is_command = previous.last().is_none_or(|t| t.kind != TokenKind::Word);
+
+        // $VAR
+        let raw = &line[start..pos];
+        if let Some(idx) = raw.rfind('$') {
+            let name = &raw[idx + 1..];
+            if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Ok((start + idx, complete_variable(name)));
+            }
+        }
+
+        if is_command && open_quote.is_none() && !word.contains('/') {
+            return Ok((start, self.complete_command(word)));
+        }
+
+        // 当前命令（最后一个管道或&之后）已经输入的单词
+        let command: Vec<&str> = previous.rsplit(|t: &Token| t.kind != TokenKind::Word)
+            .next()
+            .unwrap_or_default()
+            .iter()
+            .map(|t| t.text.as_str())
+            .collect();
+
+        // 有注册的补全规则时优先使用，没有结果再退回到文件补全
+        if let Some(spec) = find_spec(&command) {
+            let candidates = complete_with_spec(&spec, &command, word, open_quote);
+            if !candidates.is_empty() {
+                return Ok((start, candidates));
+            }
+        }
+
+        Ok((start, complete_path(word, open_quote, false)))
+    }
+}
+
+impl Validator for PshHelper {}
+
+impl Helper for PshHelper {}
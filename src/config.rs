@@ -0,0 +1,50 @@
+use std::env;
+use std::path::PathBuf;
+use dotenvy::{from_path, from_path_iter};
+
+use crate::error;
+
+// psh的配置目录：$XDG_CONFIG_HOME/psh，默认为~/.config/psh
+pub fn config_dir() -> PathBuf {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("psh"),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_else(|_| ".".to_string())).join(".config").join("psh"),
+    }
+}
+
+// psh的数据目录：$XDG_DATA_HOME/psh，默认为~/.local/share/psh
+pub fn data_dir() -> PathBuf {
+    match env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir).join("psh"),
+        _ => PathBuf::from(env::var("HOME").unwrap_or_else(|_| ".".to_string())).join(".local").join("share").join("psh"),
+    }
+}
+
+// 启动时逐行执行的rc文件
+pub fn rc_path() -> PathBuf {
+    config_dir().join("pshrc")
+}
+
+// 配置文件，格式与.env相同（KEY=VALUE），其中的设置以环境变量的形式生效
+// 已经存在的环境变量优先于配置文件
+pub fn config_path() -> PathBuf {
+    config_dir().join("config")
+}
+
+pub fn load() {
+    let path = config_path();
+    if path.is_file()
+        && let Err(e) = from_path(&path)
+    {
+        error::report(format!("Failed to load config '{}': {}", path.display(), e));
+    }
+}
+
+// 只从配置文件读取的设置，不受环境变量影响，用于不应被其他程序或项目改变的设置
+pub fn file_value(name: &str) -> Option<String> {
+    from_path_iter(config_path()).ok()?
+        .flatten()
+        .filter(|(key, _)| key == name)
+        .map(|(_, value)| value)
+        .last()
+}
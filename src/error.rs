@@ -1,6 +1,11 @@
-use std::io;
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+
+use crate::last_error;
+use crate::prompt::{color_code_for, detect_color_level};
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ShellError {
     ParseError(String),
     BuiltinError(String),
@@ -8,6 +13,7 @@ pub enum ShellError {
     ExecuteError(String),
     LLMError(String),
     RedirectionError(String),
+    HistoryError(String),
 }
 
 impl std::fmt::Display for ShellError {
@@ -19,6 +25,7 @@ impl std::fmt::Display for ShellError {
             ShellError::ExecuteError(msg) => write!(f, "Execute Error: {}", msg),
             ShellError::LLMError(msg) => write!(f, "LLM Error: {}", msg),
             ShellError::RedirectionError(msg) => write!(f, "Redirection Error: {}", msg),
+            ShellError::HistoryError(msg) => write!(f, "History Error: {}", msg),
         }
     }
 }
@@ -29,4 +36,16 @@ impl From<io::Error> for ShellError {
     fn from(err: io::Error) -> ShellError {
         ShellError::IoError(err)
     }
+}
+
+/// 向标准错误输出一条psh的错误信息，支持颜色时前缀显示为红色
+pub fn report(message: impl Display) {
+    last_error::record_stderr(format!("psh: {}\n", message).as_bytes());
+    let level = detect_color_level(io::stderr().is_terminal());
+    let red = color_code_for(level, 255, 85, 85);
+    if red.is_empty() {
+        eprintln!("psh: {}", message);
+    } else {
+        eprintln!("{}psh:\x1b[0m {}", red, message);
+    }
 }
\ No newline at end of file
@@ -6,11 +6,13 @@ pub fn execute(
     args: Vec<String>,
     stdin: Stdio,
     stdout: Stdio,
+    stderr: Stdio,
 ) -> Result<Child, ShellError> {
     Command::new(executable)
         .args(args)
         .stdin(stdin)
         .stdout(stdout)
+        .stderr(stderr)
         .spawn()
         .map_err(|e| ShellError::ExecuteError(e.to_string()))
 }
\ No newline at end of file
@@ -0,0 +1,1224 @@
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use flate2::read::ZlibDecoder;
+use sha1::{Digest, Sha1};
+
+// 提示符中的git状态
+// 直接读取.git目录下的文件，不依赖git命令
+// 所有耗时的步骤都会检查截止时间，超时后放弃剩下的部分并把timed_out置为true
+#[derive(Debug, Clone, Default)]
+pub struct GitStatus {
+    // 分支名，detached时为HEAD的短SHA
+    pub branch: String,
+    pub detached: bool,
+    pub ahead: usize,
+    pub behind: usize,
+    pub staged: bool,
+    pub dirty: bool,
+    pub untracked: bool,
+    pub timed_out: bool,
+}
+
+impl GitStatus {
+    // 如 main ↑1 ↓2 +!?
+    pub fn summary(&self) -> String {
+        let mut summary = if self.detached {
+            format!("({})", self.branch)
+        } else {
+            self.branch.clone()
+        };
+
+        if self.ahead > 0 {
+            summary.push_str(&format!(" ↑{}", self.ahead));
+        }
+        if self.behind > 0 {
+            summary.push_str(&format!(" ↓{}", self.behind));
+        }
+
+        let mut flags = String::new();
+        if self.staged {
+            flags.push('+');
+        }
+        if self.dirty {
+            flags.push('!');
+        }
+        if self.untracked {
+            flags.push('?');
+        }
+        if self.timed_out {
+            flags.push('…');
+        }
+        if !flags.is_empty() {
+            summary.push(' ');
+            summary.push_str(&flags);
+        }
+
+        summary
+    }
+}
+
+// 默认的超时时间（毫秒），可以通过PSH_GIT_TIMEOUT修改
+const DEFAULT_TIMEOUT_MS: u64 = 300;
+
+// 超时或者仓库数据无法解析时中止当前步骤
+struct Abort;
+
+type GitResult<T> = Result<T, Abort>;
+
+fn check(deadline: Instant) -> GitResult<()> {
+    if Instant::now() > deadline { Err(Abort) } else { Ok(()) }
+}
+
+/// 获取当前目录所在仓库的状态，不在仓库中或设置了PSH_GIT_PROMPT=off时返回None
+pub fn status() -> Option<GitStatus> {
+    if env::var("PSH_GIT_PROMPT").is_ok_and(|v| matches!(v.as_str(), "0" | "off" | "false" | "no")) {
+        return None;
+    }
+
+    let repo = Repo::discover(&env::current_dir().ok()?)?;
+    let timeout = env::var("PSH_GIT_TIMEOUT").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_MS);
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+
+    let head = fs::read_to_string(repo.git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    let mut status = GitStatus::default();
+
+    let (branch_ref, head_sha) = match head.strip_prefix("ref: ") {
+        Some(name) => {
+            status.branch = name.strip_prefix("refs/heads/").unwrap_or(name).to_string();
+            (Some(name), repo.resolve_ref(name))
+        }
+        None => {
+            status.branch = head.chars().take(7).collect();
+            status.detached = true;
+            (None, parse_hex(head))
+        }
+    };
+
+    let Ok(store) = ObjectStore::open(&repo.common_dir.join("objects"), deadline) else {
+        status.timed_out = true;
+        return Some(status);
+    };
+
+    // 与上游分支的差距
+    if let Some(branch_ref) = branch_ref
+        && let Some(head_sha) = head_sha
+        && let Some(upstream) = repo.upstream(branch_ref).and_then(|r| repo.resolve_ref(&r))
+        && let Ok((ahead, behind)) = ahead_behind(&store, head_sha, upstream, deadline)
+    {
+        status.ahead = ahead;
+        status.behind = behind;
+    }
+
+    // 工作区和暂存区
+    if let Ok(index) = read_index(&repo.git_dir.join("index")) {
+        status.staged = match head_sha {
+            Some(sha) => has_staged_changes(&store, sha, &index, deadline).unwrap_or(false),
+            // 还没有提交时，暂存区非空即为有暂存的修改
+            None => !index.is_empty(),
+        };
+        status.dirty = has_worktree_changes(&repo.work_tree, &index, deadline).unwrap_or(false);
+        status.untracked = has_untracked_files(&repo, &index, deadline).unwrap_or(false);
+    }
+
+    status.timed_out = Instant::now() > deadline;
+    Some(status)
+}
+
+struct Repo {
+    // 仓库的.git目录（worktree中为.git/worktrees/NAME）
+    git_dir: PathBuf,
+    // 存放objects和refs的目录，通常与git_dir相同
+    common_dir: PathBuf,
+    work_tree: PathBuf,
+}
+
+impl Repo {
+    // 从dir开始向上查找.git
+    fn discover(dir: &Path) -> Option<Repo> {
+        for dir in dir.ancestors() {
+            let dot_git = dir.join(".git");
+            let git_dir = if dot_git.is_dir() {
+                dot_git
+            } else if dot_git.is_file() {
+                // worktree和submodule中.git是一个文件：gitdir: <path>
+                let content = fs::read_to_string(&dot_git).ok()?;
+                let path = content.trim().strip_prefix("gitdir:")?.trim();
+                dir.join(path)
+            } else {
+                continue;
+            };
+
+            let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+                Ok(common) => git_dir.join(common.trim()),
+                Err(_) => git_dir.clone(),
+            };
+
+            return Some(Repo { git_dir, common_dir, work_tree: dir.to_path_buf() });
+        }
+        None
+    }
+
+    // 解析引用得到SHA，支持符号引用和packed-refs
+    fn resolve_ref(&self, name: &str) -> Option<[u8; 20]> {
+        let mut name = name.to_string();
+        for _ in 0..5 {
+            let content = fs::read_to_string(self.git_dir.join(&name))
+                .or_else(|_| fs::read_to_string(self.common_dir.join(&name)));
+            match content {
+                Ok(content) => match content.trim().strip_prefix("ref: ") {
+                    Some(target) => name = target.to_string(),
+                    None => return parse_hex(content.trim()),
+                },
+                Err(_) => return self.packed_ref(&name),
+            }
+        }
+        None
+    }
+
+    fn packed_ref(&self, name: &str) -> Option<[u8; 20]> {
+        let content = fs::read_to_string(self.common_dir.join("packed-refs")).ok()?;
+        content.lines()
+            .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+            .find_map(|line| {
+                let (sha, ref_name) = line.split_once(' ')?;
+                if ref_name == name { parse_hex(sha) } else { None }
+            })
+    }
+
+    // 从config中找到分支的上游，返回上游的引用名
+    fn upstream(&self, branch_ref: &str) -> Option<String> {
+        let branch = branch_ref.strip_prefix("refs/heads/")?;
+        let config = fs::read_to_string(self.common_dir.join("config")).ok()?;
+        let section = format!("[branch \"{}\"]", branch);
+
+        let mut in_section = false;
+        let mut remote = None;
+        let mut merge = None;
+        for line in config.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_section = line == section;
+            } else if in_section && let Some((key, value)) = line.split_once('=') {
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "remote" => remote = Some(value.trim().to_string()),
+                    "merge" => merge = Some(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+        }
+
+        let merge = merge?;
+        match remote?.as_str() {
+            "." => Some(merge),
+            remote => Some(format!("refs/remotes/{}/{}", remote, merge.strip_prefix("refs/heads/").unwrap_or(&merge))),
+        }
+    }
+
+    // 用户全局的忽略文件：core.excludesFile，默认为$XDG_CONFIG_HOME/git/ignore
+    fn excludes_file(&self) -> Option<PathBuf> {
+        let home = env::var("HOME").ok().filter(|home| !home.is_empty()).map(PathBuf::from);
+        let xdg_dir = match env::var("XDG_CONFIG_HOME") {
+            Ok(dir) if !dir.is_empty() => Some(PathBuf::from(dir).join("git")),
+            _ => home.as_ref().map(|home| home.join(".config").join("git")),
+        };
+
+        // 仓库的config优先，其次是~/.gitconfig和XDG配置
+        let configs = [
+            xdg_dir.as_ref().map(|dir| dir.join("config")),
+            home.as_ref().map(|home| home.join(".gitconfig")),
+            Some(self.common_dir.join("config")),
+        ];
+        let value = configs.iter().rev().flatten().find_map(|path| config_value(path, "core", "excludesfile"));
+        match value {
+            Some(value) => match value.strip_prefix("~/") {
+                Some(rest) => Some(home?.join(rest)),
+                None => Some(PathBuf::from(value)),
+            },
+            None => Some(xdg_dir?.join("ignore")),
+        }
+    }
+}
+
+// 读取git配置文件中[section]下key的值，section和key不区分大小写，有多个时取最后一个
+fn config_value(path: &Path, section: &str, key: &str) -> Option<String> {
+    let config = fs::read_to_string(path).ok()?;
+    let mut in_section = false;
+    let mut value = None;
+    for line in config.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix('[') {
+            in_section = name.trim_end_matches(']').trim().eq_ignore_ascii_case(section);
+        } else if in_section && let Some((name, text)) = line.split_once('=')
+            && name.trim().eq_ignore_ascii_case(key)
+        {
+            let text = text.trim();
+            value = Some(text.strip_prefix('"').and_then(|t| t.strip_suffix('"')).unwrap_or(text).to_string());
+        }
+    }
+    value
+}
+
+fn parse_hex(text: &str) -> Option<[u8; 20]> {
+    if text.len() != 40 {
+        return None;
+    }
+    let mut sha = [0u8; 20];
+    for (i, byte) in sha.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(sha)
+}
+
+fn to_hex(sha: &[u8; 20]) -> String {
+    sha.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ---------- 对象读取 ----------
+
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+// 映射到内存的pack .idx文件
+// 大仓库的.idx可能有几十MB，整个读入会超出提示符的时间预算，
+// 映射后只有查找时访问到的fanout表和SHA会被读取，也不会在进程中长期占用内存
+struct PackIndex {
+    data: *const u8,
+    len: usize,
+}
+
+impl PackIndex {
+    fn open(path: &Path) -> Option<PackIndex> {
+        let file = File::open(path).ok()?;
+        let len = usize::try_from(file.metadata().ok()?.len()).ok().filter(|&len| len > 0)?;
+        // SAFETY: 只读的私有映射，git不会原地修改.idx文件（gc时写入新文件再删除旧文件），
+        // 映射在PackIndex drop时解除，期间只通过bytes()读取
+        let data = unsafe {
+            libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        if data == libc::MAP_FAILED {
+            return None;
+        }
+        Some(PackIndex { data: data as *const u8, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: data指向open中映射的len个字节，在self存在期间有效
+        unsafe { std::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl Drop for PackIndex {
+    fn drop(&mut self) {
+        // SAFETY: 解除open中建立的映射，之后不会再访问
+        unsafe {
+            libc::munmap(self.data as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+struct ObjectStore {
+    objects_dir: PathBuf,
+    // (idx, pack文件路径)
+    packs: Vec<(PackIndex, PathBuf)>,
+}
+
+impl ObjectStore {
+    fn open(objects_dir: &Path, deadline: Instant) -> GitResult<ObjectStore> {
+        let mut packs = Vec::new();
+        if let Ok(entries) = fs::read_dir(objects_dir.join("pack")) {
+            for entry in entries.flatten() {
+                check(deadline)?;
+                let path = entry.path();
+                if path.extension().is_none_or(|ext| ext != "idx") {
+                    continue;
+                }
+                if let Some(idx) = PackIndex::open(&path) {
+                    packs.push((idx, path.with_extension("pack")));
+                }
+            }
+        }
+
+        Ok(ObjectStore { objects_dir: objects_dir.to_path_buf(), packs })
+    }
+
+    // 读取对象，返回(类型, 内容)
+    fn read(&self, sha: &[u8; 20]) -> GitResult<(u8, Vec<u8>)> {
+        let hex = to_hex(sha);
+        if let Ok(file) = File::open(self.objects_dir.join(&hex[..2]).join(&hex[2..])) {
+            let data = inflate(file)?;
+            let nul = data.iter().position(|&b| b == 0).ok_or(Abort)?;
+            let kind = match &data[..data.iter().position(|&b| b == b' ').ok_or(Abort)?] {
+                b"commit" => OBJ_COMMIT,
+                b"tree" => OBJ_TREE,
+                b"blob" => 3,
+                b"tag" => 4,
+                _ => return Err(Abort),
+            };
+            return Ok((kind, data[nul + 1..].to_vec()));
+        }
+
+        for (idx, pack) in &self.packs {
+            if let Some(offset) = idx_lookup(idx.bytes(), sha) {
+                return self.read_packed(pack, offset, 0);
+            }
+        }
+        Err(Abort)
+    }
+
+    fn read_packed(&self, pack: &Path, offset: u64, depth: usize) -> GitResult<(u8, Vec<u8>)> {
+        if depth > 64 {
+            return Err(Abort);
+        }
+
+        let mut file = File::open(pack).map_err(|_| Abort)?;
+        file.seek(SeekFrom::Start(offset)).map_err(|_| Abort)?;
+        let mut header = [0u8; 64];
+        let read = file.read(&mut header).map_err(|_| Abort)?;
+        let header = &header[..read];
+
+        // 类型和解压后的大小
+        let mut pos = 0;
+        let mut byte = *header.get(pos).ok_or(Abort)?;
+        let kind = (byte >> 4) & 7;
+        let mut size = (byte & 15) as u64;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            pos += 1;
+            byte = *header.get(pos).ok_or(Abort)?;
+            size |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+        }
+        pos += 1;
+
+        // delta对象的基准对象
+        let base = match kind {
+            OBJ_OFS_DELTA => {
+                let mut byte = *header.get(pos).ok_or(Abort)?;
+                let mut distance = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    pos += 1;
+                    byte = *header.get(pos).ok_or(Abort)?;
+                    distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+                }
+                pos += 1;
+                Some(self.read_packed(pack, offset.checked_sub(distance).ok_or(Abort)?, depth + 1)?)
+            }
+            OBJ_REF_DELTA => {
+                let base_sha: [u8; 20] = header.get(pos..pos + 20).ok_or(Abort)?.try_into().map_err(|_| Abort)?;
+                pos += 20;
+                Some(self.read(&base_sha)?)
+            }
+            _ => None,
+        };
+
+        file.seek(SeekFrom::Start(offset + pos as u64)).map_err(|_| Abort)?;
+        let data = inflate(BufReader::new(file))?;
+        if data.len() as u64 != size {
+            return Err(Abort);
+        }
+
+        match base {
+            Some((base_kind, base_data)) => Ok((base_kind, apply_delta(&base_data, &data)?)),
+            None => Ok((kind, data)),
+        }
+    }
+}
+
+// 解压一个zlib数据流（git的对象都用zlib压缩），读到数据流结束为止
+fn inflate(reader: impl Read) -> GitResult<Vec<u8>> {
+    let mut data = Vec::new();
+    ZlibDecoder::new(reader).read_to_end(&mut data).map_err(|_| Abort)?;
+    Ok(data)
+}
+
+// 在version 2的.idx文件中查找对象在pack中的偏移
+fn idx_lookup(idx: &[u8], sha: &[u8; 20]) -> Option<u64> {
+    if idx.len() < 8 + 1024 || idx[..4] != [0xff, b't', b'O', b'c'] || read_u32(idx, 4)? != 2 {
+        return None;
+    }
+
+    let fanout = |i: usize| read_u32(idx, 8 + i * 4).map(|n| n as usize);
+    let total = fanout(255)?;
+    let mut low = if sha[0] == 0 { 0 } else { fanout(sha[0] as usize - 1)? };
+    let mut high = fanout(sha[0] as usize)?;
+
+    let shas = 8 + 1024;
+    while low < high {
+        let mid = (low + high) / 2;
+        let candidate = idx.get(shas + mid * 20..shas + mid * 20 + 20)?;
+        match candidate.cmp(&sha[..]) {
+            std::cmp::Ordering::Equal => {
+                let offsets = shas + total * 24;
+                let offset = read_u32(idx, offsets + mid * 4)?;
+                if offset & 0x8000_0000 == 0 {
+                    return Some(offset as u64);
+                }
+                // 超过2GB的偏移保存在额外的8字节表中
+                let large = offsets + total * 4 + (offset & 0x7fff_ffff) as usize * 8;
+                return Some(((read_u32(idx, large)? as u64) << 32) | read_u32(idx, large + 4)? as u64);
+            }
+            std::cmp::Ordering::Less => low = mid + 1,
+            std::cmp::Ordering::Greater => high = mid,
+        }
+    }
+    None
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+// 应用git的delta：由复制基准对象片段和插入新数据两种指令组成
+fn apply_delta(base: &[u8], delta: &[u8]) -> GitResult<Vec<u8>> {
+    let mut pos = 0;
+    let varint = |pos: &mut usize| -> GitResult<usize> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *delta.get(*pos).ok_or(Abort)?;
+            *pos += 1;
+            value |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    };
+
+    let base_size = varint(&mut pos)?;
+    let result_size = varint(&mut pos)?;
+    if base_size != base.len() {
+        return Err(Abort);
+    }
+
+    let mut result = Vec::with_capacity(result_size);
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+
+        if op & 0x80 != 0 {
+            // 复制：低4位表示偏移的字节，接下来3位表示长度的字节
+            let mut offset = 0usize;
+            let mut length = 0usize;
+            for i in 0..4 {
+                if op & (1 << i) != 0 {
+                    offset |= (*delta.get(pos).ok_or(Abort)? as usize) << (i * 8);
+                    pos += 1;
+                }
+            }
+            for i in 0..3 {
+                if op & (0x10 << i) != 0 {
+                    length |= (*delta.get(pos).ok_or(Abort)? as usize) << (i * 8);
+                    pos += 1;
+                }
+            }
+            if length == 0 {
+                length = 0x10000;
+            }
+            result.extend_from_slice(base.get(offset..offset + length).ok_or(Abort)?);
+        } else if op != 0 {
+            // 插入接下来的op个字节
+            result.extend_from_slice(delta.get(pos..pos + op as usize).ok_or(Abort)?);
+            pos += op as usize;
+        } else {
+            return Err(Abort);
+        }
+    }
+
+    if result.len() != result_size {
+        return Err(Abort);
+    }
+    Ok(result)
+}
+
+// ---------- 提交与树 ----------
+
+struct Commit {
+    tree: [u8; 20],
+    parents: Vec<[u8; 20]>,
+    time: i64,
+}
+
+fn read_commit(store: &ObjectStore, sha: &[u8; 20]) -> GitResult<Commit> {
+    let (kind, data) = store.read(sha)?;
+    if kind != OBJ_COMMIT {
+        return Err(Abort);
+    }
+
+    let text = String::from_utf8_lossy(&data);
+    let mut commit = Commit { tree: [0; 20], parents: Vec::new(), time: 0 };
+    for line in text.lines() {
+        if line.is_empty() {
+            break;
+        }
+        if let Some(tree) = line.strip_prefix("tree ") {
+            commit.tree = parse_hex(tree).ok_or(Abort)?;
+        } else if let Some(parent) = line.strip_prefix("parent ") {
+            commit.parents.push(parse_hex(parent).ok_or(Abort)?);
+        } else if let Some(committer) = line.strip_prefix("committer ") {
+            // committer Name <email> 1700000000 +0800
+            commit.time = committer.rsplit(' ').nth(1).and_then(|t| t.parse().ok()).unwrap_or(0);
+        }
+    }
+    Ok(commit)
+}
+
+// 计算local领先和落后upstream的提交数
+// 按提交时间从新到旧遍历两边的祖先，直到队列中只剩下两边共同的祖先
+fn ahead_behind(store: &ObjectStore, local: [u8; 20], upstream: [u8; 20], deadline: Instant) -> GitResult<(usize, usize)> {
+    const LOCAL: u8 = 1;
+    const UPSTREAM: u8 = 2;
+    const BOTH: u8 = LOCAL | UPSTREAM;
+
+    let mut flags: HashMap<[u8; 20], u8> = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    // 在队列中的提交，每个提交在队列中最多出现一次，出队时读取它最新的标记
+    let mut queued: HashSet<[u8; 20]> = HashSet::new();
+    // 队列中还没有被两边同时到达的提交数
+    let mut pending = 0usize;
+
+    for (sha, flag) in [(local, LOCAL), (upstream, UPSTREAM)] {
+        *flags.entry(sha).or_insert(0) |= flag;
+        if queued.insert(sha) {
+            queue.push((read_commit(store, &sha)?.time, sha));
+        }
+    }
+    pending += queued.iter().filter(|sha| flags[*sha] != BOTH).count();
+
+    while pending > 0 {
+        check(deadline)?;
+        let Some((_, sha)) = queue.pop() else { break };
+        queued.remove(&sha);
+        let flag = flags[&sha];
+        if flag != BOTH {
+            pending -= 1;
+        }
+
+        for parent in read_commit(store, &sha)?.parents {
+            let old = flags.get(&parent).copied().unwrap_or(0);
+            let new = old | flag;
+            if new == old {
+                continue;
+            }
+            flags.insert(parent, new);
+            if queued.contains(&parent) {
+                // 已经在队列中的提交被另一边到达了
+                if new == BOTH {
+                    pending -= 1;
+                }
+            } else {
+                queued.insert(parent);
+                queue.push((read_commit(store, &parent)?.time, parent));
+                if new != BOTH {
+                    pending += 1;
+                }
+            }
+        }
+    }
+
+    let ahead = flags.values().filter(|&&f| f == LOCAL).count();
+    let behind = flags.values().filter(|&&f| f == UPSTREAM).count();
+    Ok((ahead, behind))
+}
+
+// 把树展开成 路径 -> (mode, sha)
+fn flatten_tree(store: &ObjectStore, sha: &[u8; 20], prefix: &str, files: &mut HashMap<String, (u32, [u8; 20])>, deadline: Instant) -> GitResult<()> {
+    check(deadline)?;
+    let (kind, data) = store.read(sha)?;
+    if kind != OBJ_TREE {
+        return Err(Abort);
+    }
+
+    // 每一项是 "<mode> <name>\0<20字节sha>"
+    let mut pos = 0;
+    while pos < data.len() {
+        let space = pos + data[pos..].iter().position(|&b| b == b' ').ok_or(Abort)?;
+        let nul = space + data[space..].iter().position(|&b| b == 0).ok_or(Abort)?;
+        let mode = u32::from_str_radix(std::str::from_utf8(&data[pos..space]).map_err(|_| Abort)?, 8).map_err(|_| Abort)?;
+        let name = String::from_utf8_lossy(&data[space + 1..nul]);
+        let entry_sha: [u8; 20] = data.get(nul + 1..nul + 21).ok_or(Abort)?.try_into().map_err(|_| Abort)?;
+        pos = nul + 21;
+
+        let path = if prefix.is_empty() { name.to_string() } else { format!("{}/{}", prefix, name) };
+        if mode == 0o40000 {
+            flatten_tree(store, &entry_sha, &path, files, deadline)?;
+        } else {
+            files.insert(path, (mode, entry_sha));
+        }
+    }
+    Ok(())
+}
+
+// ---------- 暂存区 ----------
+
+struct IndexEntry {
+    path: String,
+    mode: u32,
+    size: u32,
+    mtime: (u32, u32),
+    sha: [u8; 20],
+    stage: u16,
+    // assume-valid或skip-worktree，不检查工作区
+    skip: bool,
+    intent_to_add: bool,
+}
+
+// 读取.git/index，支持version 2到4
+fn read_index(path: &Path) -> GitResult<Vec<IndexEntry>> {
+    let data = fs::read(path).map_err(|_| Abort)?;
+    if data.len() < 12 || &data[..4] != b"DIRC" {
+        return Err(Abort);
+    }
+    let version = read_u32(&data, 4).ok_or(Abort)?;
+    let count = read_u32(&data, 8).ok_or(Abort)? as usize;
+    if !(2..=4).contains(&version) {
+        return Err(Abort);
+    }
+
+    let mut entries: Vec<IndexEntry> = Vec::with_capacity(count);
+    let mut pos = 12;
+    for _ in 0..count {
+        let field = |i: usize| read_u32(&data, pos + i * 4).ok_or(Abort);
+        let mtime = (field(2)?, field(3)?);
+        let mode = field(6)?;
+        let size = field(9)?;
+        let sha: [u8; 20] = data.get(pos + 40..pos + 60).ok_or(Abort)?.try_into().map_err(|_| Abort)?;
+        let flags = read_u16(&data, pos + 60).ok_or(Abort)?;
+        let mut header_len = 62;
+        let mut extended = 0;
+        if flags & 0x4000 != 0 {
+            extended = read_u16(&data, pos + 62).ok_or(Abort)?;
+            header_len += 2;
+        }
+
+        let name_start = pos + header_len;
+        let (path, next) = if version == 4 {
+            // 路径相对上一项做了前缀压缩：先是要去掉的字节数，再是以NUL结尾的后缀
+            let mut p = name_start;
+            let mut byte = *data.get(p).ok_or(Abort)?;
+            let mut strip = (byte & 0x7f) as usize;
+            while byte & 0x80 != 0 {
+                p += 1;
+                byte = *data.get(p).ok_or(Abort)?;
+                strip = ((strip + 1) << 7) | (byte & 0x7f) as usize;
+            }
+            p += 1;
+            let nul = p + data[p..].iter().position(|&b| b == 0).ok_or(Abort)?;
+            let previous = entries.last().map_or("", |e| e.path.as_str());
+            let keep = previous.len().checked_sub(strip).ok_or(Abort)?;
+            let path = format!("{}{}", &previous[..keep], String::from_utf8_lossy(&data[p..nul]));
+            (path, nul + 1)
+        } else {
+            let nul = name_start + data[name_start..].iter().position(|&b| b == 0).ok_or(Abort)?;
+            // 每一项按8字节对齐，且至少有一个NUL
+            let entry_len = (nul - pos + 8) / 8 * 8;
+            (String::from_utf8_lossy(&data[name_start..nul]).to_string(), pos + entry_len)
+        };
+
+        entries.push(IndexEntry {
+            path,
+            mode,
+            size,
+            mtime,
+            sha,
+            stage: (flags >> 12) & 3,
+            skip: flags & 0x8000 != 0 || extended & 0x4000 != 0,
+            intent_to_add: extended & 0x2000 != 0,
+        });
+        pos = next;
+    }
+
+    Ok(entries)
+}
+
+// 暂存区与HEAD是否不同
+fn has_staged_changes(store: &ObjectStore, head: [u8; 20], index: &[IndexEntry], deadline: Instant) -> GitResult<bool> {
+    // 有冲突时视为有暂存的修改
+    if index.iter().any(|e| e.stage != 0) {
+        return Ok(true);
+    }
+
+    let mut files = HashMap::new();
+    flatten_tree(store, &read_commit(store, &head)?.tree, "", &mut files, deadline)?;
+
+    let staged: Vec<&IndexEntry> = index.iter().filter(|e| !e.intent_to_add).collect();
+    if staged.len() != files.len() {
+        return Ok(true);
+    }
+    Ok(staged.iter().any(|e| files.get(&e.path) != Some(&(e.mode, e.sha))))
+}
+
+// 工作区中已跟踪的文件是否被修改或删除
+fn has_worktree_changes(work_tree: &Path, index: &[IndexEntry], deadline: Instant) -> GitResult<bool> {
+    for entry in index {
+        check(deadline)?;
+        // 跳过submodule
+        if entry.skip || entry.mode == 0o160000 {
+            continue;
+        }
+
+        let path = work_tree.join(&entry.path);
+        let Ok(meta) = fs::symlink_metadata(&path) else {
+            return Ok(true);
+        };
+        if meta.size() as u32 != entry.size {
+            return Ok(true);
+        }
+        if meta.is_file() && (meta.mode() & 0o111 != 0) != (entry.mode & 0o111 != 0) {
+            return Ok(true);
+        }
+        if (meta.mtime() as u32, meta.mtime_nsec() as u32) == entry.mtime {
+            continue;
+        }
+
+        // 修改时间变了，计算内容的哈希确认是否真的修改
+        let content = if meta.file_type().is_symlink() {
+            fs::read_link(&path).map(|target| target.as_os_str().as_bytes().to_vec())
+        } else {
+            fs::read(&path)
+        }.map_err(|_| Abort)?;
+        let sha: [u8; 20] = Sha1::new()
+            .chain_update(format!("blob {}\0", content.len()))
+            .chain_update(&content)
+            .finalize()
+            .into();
+        if sha != entry.sha {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// ---------- 未跟踪的文件 ----------
+
+struct IgnoreRule {
+    // 规则所在的目录（相对工作区根目录），根目录为空字符串
+    base: String,
+    pattern: String,
+    negate: bool,
+    dir_only: bool,
+    // 包含/的规则相对base匹配完整路径，否则只匹配文件名
+    anchored: bool,
+}
+
+fn load_ignore_rules(path: &Path, base: &str, rules: &mut Vec<IgnoreRule>) {
+    let Ok(content) = fs::read_to_string(path) else { return };
+    for line in content.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (negate, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        rules.push(IgnoreRule {
+            base: base.to_string(),
+            anchored: line.contains('/'),
+            pattern: line.trim_start_matches('/').to_string(),
+            negate,
+            dir_only,
+        });
+    }
+}
+
+// 后面的规则优先
+fn is_ignored(rules: &[IgnoreRule], path: &str, is_dir: bool) -> bool {
+    for rule in rules.iter().rev() {
+        if rule.dir_only && !is_dir {
+            continue;
+        }
+        let relative = if rule.base.is_empty() {
+            path
+        } else {
+            match path.strip_prefix(&rule.base).and_then(|p| p.strip_prefix('/')) {
+                Some(relative) => relative,
+                None => continue,
+            }
+        };
+        let text = if rule.anchored { relative } else { relative.rsplit('/').next().unwrap_or(relative) };
+        if glob_match(rule.pattern.as_bytes(), text.as_bytes()) {
+            return !rule.negate;
+        }
+    }
+    false
+}
+
+// gitignore风格的通配符：* 和 ? 不匹配/，** 匹配任意层目录，[...] 匹配字符集合
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        // 末尾的**匹配剩下的所有内容，如 out/**
+        Some(b'*') if pattern == b"**" => true,
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = pattern[2..].strip_prefix(b"/").unwrap_or(&pattern[2..]);
+            (0..=text.len()).any(|i| (i == 0 || text[i - 1] == b'/') && glob_match(rest, &text[i..]))
+                || glob_match(&pattern[2..], text)
+        }
+        Some(b'*') => {
+            (0..=text.len()).take_while(|&i| i == 0 || text[i - 1] != b'/').any(|i| glob_match(&pattern[1..], &text[i..]))
+        }
+        Some(b'?') => text.first().is_some_and(|&c| c != b'/') && glob_match(&pattern[1..], &text[1..]),
+        Some(b'[') => {
+            let Some(end) = pattern.iter().skip(2).position(|&c| c == b']').map(|i| i + 2) else {
+                return text.first() == Some(&b'[') && glob_match(&pattern[1..], &text[1..]);
+            };
+            let Some(&c) = text.first() else { return false };
+            let (negate, class) = match pattern[1] {
+                b'!' | b'^' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            matched != negate && c != b'/' && glob_match(&pattern[end + 1..], &text[1..])
+        }
+        Some(b'\\') if pattern.len() > 1 => text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..]),
+        Some(&p) => text.first() == Some(&p) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+fn has_untracked_files(repo: &Repo, index: &[IndexEntry], deadline: Instant) -> GitResult<bool> {
+    let tracked: HashSet<&str> = index.iter().map(|e| e.path.as_str()).collect();
+    let mut rules = Vec::new();
+    // 优先级从低到高：core.excludesFile、info/exclude、各目录的.gitignore
+    if let Some(path) = repo.excludes_file() {
+        load_ignore_rules(&path, "", &mut rules);
+    }
+    load_ignore_rules(&repo.common_dir.join("info").join("exclude"), "", &mut rules);
+    find_untracked(&repo.work_tree, "", &tracked, &mut rules, deadline)
+}
+
+// 递归查找没有被跟踪也没有被忽略的文件，找到一个就返回
+fn find_untracked(work_tree: &Path, dir: &str, tracked: &HashSet<&str>, rules: &mut Vec<IgnoreRule>, deadline: Instant) -> GitResult<bool> {
+    check(deadline)?;
+    let full_dir = work_tree.join(dir);
+    let rule_count = rules.len();
+    load_ignore_rules(&full_dir.join(".gitignore"), dir, rules);
+
+    let mut found = false;
+    if let Ok(entries) = fs::read_dir(&full_dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else { continue };
+            if name == ".git" {
+                continue;
+            }
+
+            let path = if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) };
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            if is_ignored(rules, &path, is_dir) {
+                continue;
+            }
+
+            found = if is_dir {
+                // submodule目录本身就是一个跟踪的条目
+                !tracked.contains(path.as_str()) && find_untracked(work_tree, &path, tracked, rules, deadline)?
+            } else {
+                !tracked.contains(path.as_str())
+            };
+            if found {
+                break;
+            }
+        }
+    }
+
+    rules.truncate(rule_count);
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    // 在dir中执行git命令并返回标准输出，不读取用户的配置
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_AUTHOR_NAME", "psh")
+            .env("GIT_AUTHOR_EMAIL", "psh@example.com")
+            .env("GIT_COMMITTER_NAME", "psh")
+            .env("GIT_COMMITTER_EMAIL", "psh@example.com")
+            .output()
+            .expect("failed to run git");
+        assert!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    fn init() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        git(dir.path(), &["init", "-q", "-b", "main"]);
+        dir
+    }
+
+    // 创建一个空提交，提交时间为n秒，返回它的SHA
+    fn commit(dir: &Path, n: i64) -> [u8; 20] {
+        let date = format!("{} +0000", 1_700_000_000 + n);
+        let output = Command::new("git")
+            .args(["-c", "user.name=psh", "-c", "user.email=psh@example.com", "commit", "-q", "--allow-empty", "-m"])
+            .arg(format!("commit {}", n))
+            .current_dir(dir)
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_AUTHOR_DATE", &date)
+            .env("GIT_COMMITTER_DATE", &date)
+            .output()
+            .expect("failed to run git");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        head(dir)
+    }
+
+    fn head(dir: &Path) -> [u8; 20] {
+        parse_hex(&git(dir, &["rev-parse", "HEAD"])).unwrap()
+    }
+
+    fn store(dir: &Path) -> ObjectStore {
+        ObjectStore::open(&dir.join(".git/objects"), Instant::now() + Duration::from_secs(10)).unwrap_or_else(|_| panic!("open aborted"))
+    }
+
+    fn count(dir: &Path, local: [u8; 20], upstream: [u8; 20]) -> (usize, usize) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        ahead_behind(&store(dir), local, upstream, deadline).unwrap_or_else(|_| panic!("ahead_behind aborted"))
+    }
+
+    #[test]
+    fn ahead_behind_counts_diverged_branches() {
+        let repo = init();
+        let dir = repo.path();
+        for n in 0..3 {
+            commit(dir, n);
+        }
+        git(dir, &["branch", "upstream"]);
+        commit(dir, 3);
+        let local = commit(dir, 4);
+        git(dir, &["checkout", "-q", "upstream"]);
+        let upstream = commit(dir, 5);
+
+        assert_eq!(count(dir, local, upstream), (2, 1));
+        assert_eq!(count(dir, upstream, local), (1, 2));
+        assert_eq!(count(dir, local, local), (0, 0));
+
+        // 合并之后upstream的提交也是local的祖先
+        git(dir, &["checkout", "-q", "main"]);
+        git(dir, &["-c", "user.name=psh", "-c", "user.email=psh@example.com", "merge", "-q", "--no-edit", "upstream"]);
+        assert_eq!(count(dir, head(dir), upstream), (3, 0));
+
+        // 打包之后结果不变
+        git(dir, &["gc", "-q"]);
+        assert_eq!(count(dir, local, upstream), (2, 1));
+    }
+
+    #[test]
+    fn ahead_behind_stops_at_merge_base() {
+        let repo = init();
+        let dir = repo.path();
+        let history: Vec<[u8; 20]> = (0..30).map(|n| commit(dir, n)).collect();
+        let base = *history.last().unwrap();
+        let next = commit(dir, 30);
+
+        // 删除合并基点之前的所有提交，遍历越过基点就会失败
+        for sha in &history[..history.len() - 1] {
+            let hex = to_hex(sha);
+            fs::remove_file(dir.join(".git/objects").join(&hex[..2]).join(&hex[2..])).unwrap();
+        }
+
+        assert_eq!(count(dir, next, base), (1, 0));
+        assert_eq!(count(dir, base, next), (0, 1));
+    }
+
+    #[test]
+    fn reads_packed_objects_with_deltas() {
+        let repo = init();
+        let dir = repo.path();
+        let mut content: String = (0..200).map(|i| format!("line {}\n", i)).collect();
+        for n in 0..3 {
+            content.push_str(&format!("change {}\n", n));
+            fs::write(dir.join("file.txt"), &content).unwrap();
+            git(dir, &["add", "file.txt"]);
+            commit(dir, n);
+        }
+        git(dir, &["repack", "-adq"]);
+
+        let pack_idx = fs::read_dir(dir.join(".git/objects/pack")).unwrap()
+            .flatten()
+            .map(|e| e.path())
+            .find(|p| p.extension().is_some_and(|ext| ext == "idx"))
+            .unwrap();
+        let verify = git(dir, &["verify-pack", "-v", pack_idx.to_str().unwrap()]);
+        assert!(verify.contains("chain length = 1"), "expected a delta in the pack:\n{}", verify);
+
+        let store = store(dir);
+        for rev in ["HEAD:file.txt", "HEAD~1:file.txt", "HEAD~2:file.txt"] {
+            let sha = parse_hex(&git(dir, &["rev-parse", rev])).unwrap();
+            let (kind, data) = store.read(&sha).unwrap_or_else(|_| panic!("failed to read {}", rev));
+            assert_eq!(kind, 3);
+            assert_eq!(String::from_utf8(data).unwrap(), git(dir, &["cat-file", "blob", rev]) + "\n");
+        }
+    }
+
+    #[test]
+    fn applies_delta_instructions() {
+        let base = b"hello world";
+        // 基准11字节，结果10字节：复制base[0..6]，插入"rust"
+        let delta = [&[11, 10, 0x90, 6, 4][..], b"rust"].concat();
+        assert_eq!(apply_delta(base, &delta).ok().unwrap(), b"hello rust");
+
+        // 带偏移的复制：复制base[6..11]
+        let delta = [&[11, 5, 0x91, 6, 5][..]].concat();
+        assert_eq!(apply_delta(base, &delta).ok().unwrap(), b"world");
+
+        // 基准大小不符、复制越界、结果大小不符、保留的0指令
+        assert!(apply_delta(base, &[10, 1, 1, b'x']).is_err());
+        assert!(apply_delta(base, &[11, 12, 0x90, 12]).is_err());
+        assert!(apply_delta(base, &[11, 2, 1, b'x']).is_err());
+        assert!(apply_delta(base, &[11, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn reads_index_versions() {
+        let repo = init();
+        let dir = repo.path();
+        fs::create_dir(dir.join("dir")).unwrap();
+        fs::write(dir.join("a.txt"), "a\n").unwrap();
+        fs::write(dir.join("dir/b.txt"), "b\n").unwrap();
+        fs::write(dir.join("dir/c.txt"), "c\n").unwrap();
+        fs::write(dir.join("new.txt"), "new\n").unwrap();
+        fs::set_permissions(dir.join("dir/c.txt"), fs::Permissions::from_mode(0o755)).unwrap();
+        git(dir, &["add", "a.txt", "dir"]);
+
+        let index_path = dir.join(".git/index");
+        for version in ["2", "4"] {
+            git(dir, &["update-index", "--index-version", version]);
+            let index = read_index(&index_path).ok().unwrap();
+            let paths: Vec<&str> = index.iter().map(|e| e.path.as_str()).collect();
+            assert_eq!(paths, ["a.txt", "dir/b.txt", "dir/c.txt"], "version {}", version);
+            assert_eq!(index[0].mode, 0o100644);
+            assert_eq!(index[2].mode, 0o100755);
+            assert_eq!(index[0].size, 2);
+            assert_eq!(to_hex(&index[1].sha), git(dir, &["hash-object", "dir/b.txt"]));
+            assert!(index.iter().all(|e| e.stage == 0 && !e.skip && !e.intent_to_add));
+            assert!(!has_worktree_changes(dir, &index, Instant::now() + Duration::from_secs(10)).ok().unwrap());
+        }
+
+        // 扩展标记需要version 3
+        git(dir, &["update-index", "--index-version", "2"]);
+        git(dir, &["add", "-N", "new.txt"]);
+        git(dir, &["update-index", "--skip-worktree", "a.txt"]);
+        assert_eq!(read_u32(&fs::read(&index_path).unwrap(), 4), Some(3));
+        let index = read_index(&index_path).ok().unwrap();
+        let entry = |path: &str| index.iter().find(|e| e.path == path).unwrap();
+        assert!(entry("a.txt").skip);
+        assert!(entry("new.txt").intent_to_add);
+        assert!(!entry("dir/b.txt").skip && !entry("dir/b.txt").intent_to_add);
+
+        fs::write(dir.join("dir/b.txt"), "B\n").unwrap();
+        assert!(has_worktree_changes(dir, &index, Instant::now() + Duration::from_secs(10)).ok().unwrap());
+    }
+
+    #[test]
+    fn matches_gitignore_globs() {
+        let matches = |pattern: &str, text: &str| glob_match(pattern.as_bytes(), text.as_bytes());
+        assert!(matches("*.log", "error.log"));
+        assert!(!matches("*.log", "logs/error.log"));
+        assert!(!matches("*.log", "error.txt"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file/.txt"));
+        assert!(matches("**/build", "build"));
+        assert!(matches("**/build", "a/b/build"));
+        assert!(matches("src/**/*.rs", "src/main.rs"));
+        assert!(matches("src/**/*.rs", "src/a/b/main.rs"));
+        assert!(!matches("src/**/*.rs", "lib/main.rs"));
+        assert!(matches("out/**", "out/a/b"));
+        assert!(matches("[abc].txt", "b.txt"));
+        assert!(matches("[a-c].txt", "c.txt"));
+        assert!(!matches("[a-c].txt", "d.txt"));
+        assert!(matches("[!a-c].txt", "d.txt"));
+        assert!(!matches("[^a-c].txt", "a.txt"));
+        assert!(matches("\\*.txt", "*.txt"));
+        assert!(!matches("\\*.txt", "a.txt"));
+        assert!(matches("[", "["));
+    }
+
+    #[test]
+    fn applies_ignore_rules_in_order() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(".gitignore");
+        fs::write(&path, "# comment\n*.log\n!keep.log\nbuild/\n/root.txt\ndocs/*.md\n").unwrap();
+        let mut rules = Vec::new();
+        load_ignore_rules(&path, "", &mut rules);
+        load_ignore_rules(&path, "sub", &mut rules);
+
+        assert!(is_ignored(&rules, "a.log", false));
+        assert!(is_ignored(&rules, "deep/dir/a.log", false));
+        assert!(!is_ignored(&rules, "keep.log", false));
+        assert!(is_ignored(&rules, "build", true));
+        assert!(!is_ignored(&rules, "build", false));
+        assert!(is_ignored(&rules, "root.txt", false));
+        assert!(!is_ignored(&rules, "other/root.txt", false));
+        assert!(is_ignored(&rules, "sub/root.txt", false));
+        assert!(is_ignored(&rules, "docs/a.md", false));
+        assert!(!is_ignored(&rules, "docs/a/b.md", false));
+    }
+
+    #[test]
+    fn honors_core_excludes_file() {
+        let repo = init();
+        let dir = repo.path();
+        let ignore = dir.join("global-ignore");
+        fs::write(&ignore, "*.swp\nglobal-ignore\n").unwrap();
+        git(dir, &["config", "core.excludesFile", ignore.to_str().unwrap()]);
+        fs::write(dir.join("a.swp"), "").unwrap();
+
+        let repo = Repo::discover(dir).unwrap();
+        assert_eq!(repo.excludes_file().as_deref(), Some(ignore.as_path()));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert!(has_untracked_files(&repo, &[], deadline).is_ok_and(|found| !found));
+
+        // info/exclude和.gitignore优先于core.excludesFile
+        fs::write(dir.join(".git/info/exclude"), "!a.swp\n").unwrap();
+        assert!(has_untracked_files(&repo, &[], deadline).is_ok_and(|found| found));
+    }
+
+    #[test]
+    fn reads_config_values() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config");
+        fs::write(&path, "[Core]\n\texcludesFile = \"~/my ignore\"\n[user]\n\tname = x\n[core]\n\tbare = false\n").unwrap();
+        assert_eq!(config_value(&path, "core", "excludesfile").as_deref(), Some("~/my ignore"));
+        assert_eq!(config_value(&path, "core", "name"), None);
+        assert_eq!(config_value(&path, "user", "name").as_deref(), Some("x"));
+    }
+}
\ No newline at end of file
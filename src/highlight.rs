@@ -0,0 +1,224 @@
+use std::borrow::Cow;
+use std::path::Path;
+use rustyline::highlight::{CmdKind, Highlighter};
+
+use crate::completion::PshHelper;
+use crate::history;
+use crate::hint;
+use crate::parser::{tokenize, TokenKind, BUILTINS};
+use crate::prompt::{color_code, color_enabled, visible_width};
+use crate::theme;
+
+const RESET: &str = "\x1b[0m";
+
+// 用当前主题中element的颜色为文本上色
+pub fn paint(text: &str, element: &str) -> String {
+    let (r, g, b) = theme::with_current(|theme| theme.highlight_color(element));
+    let code = color_code(r, g, b);
+    // 不使用颜色时color_code为空，也不需要RESET
+    if code.is_empty() {
+        return text.to_string();
+    }
+    format!("{}{}{}", code, text, RESET)
+}
+
+// 显示在输入行最右侧的提示符
+pub struct RightPrompt {
+    // 去掉了\x01和\x02标记的文本
+    text: String,
+    width: usize,
+    // 左侧提示符最后一行的宽度
+    prompt_width: usize,
+    columns: usize,
+}
+
+impl RightPrompt {
+    pub fn new(text: &str, prompt: &str, columns: usize) -> Self {
+        RightPrompt {
+            text: text.replace(['\x01', '\x02'], ""),
+            width: visible_width(text),
+            prompt_width: visible_width(prompt.rsplit('\n').next().unwrap_or("")),
+            columns,
+        }
+    }
+
+    // 保存光标，移动到右侧绘制，再恢复光标
+    // 这些内容跟在输入行后面输出，rustyline计算布局时不会把它们算进去
+    // 输入的内容（包括自动建议）会碰到右侧提示符时不显示
+    fn render(&self, line: &str, pos: usize) -> Option<String> {
+        let hint_width = if pos == line.len() {
+            history::suggest(line).map_or(0, |hint| visible_width(&hint))
+        } else {
+            0
+        };
+        let used = self.prompt_width + visible_width(line) + hint_width;
+        if line.contains('\n') || self.width == 0 || used + 1 + self.width > self.columns {
+            return None;
+        }
+        Some(format!("\x1b7\x1b[{}G{}\x1b8", self.columns - self.width + 1, self.text))
+    }
+}
+
+impl PshHelper {
+    // 判断命令名是否可以执行：内建命令、路径指向的可执行文件或PATH中的命令
+    fn command_element(&self, name: &str) -> &'static str {
+        if BUILTINS.contains(&name) || name == "exit" || name == "quit" {
+            "builtin"
+        } else if name.contains('/') {
+            if Path::new(name).is_file() { "command" } else { "unknown" }
+        } else if self.is_executable(name) {
+            "command"
+        } else {
+            "unknown"
+        }
+    }
+}
+
+// 高亮参数：引号括起的部分按字符串上色，引号外和双引号内的$VAR按变量上色
+fn highlight_argument(raw: &str) -> String {
+    let mut result = String::new();
+    let mut chars = raw.char_indices().peekable();
+
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\'' | '"' => {
+                // 找到闭合的引号，没有的话一直到结尾
+                let end = raw[i + 1..].find(ch).map_or(raw.len(), |j| i + 1 + j + 1);
+                if ch == '"' {
+                    result.push_str(&highlight_double_quoted(&raw[i..end]));
+                } else {
+                    result.push_str(&paint(&raw[i..end], "string"));
+                }
+                while chars.peek().is_some_and(|&(j, _)| j < end) {
+                    chars.next();
+                }
+            }
+            '\\' => {
+                result.push(ch);
+                if let Some((_, escaped)) = chars.next() {
+                    result.push(escaped);
+                }
+            }
+            '$' => {
+                let end = raw[i + 1..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .map_or(raw.len(), |j| i + 1 + j);
+                result.push_str(&paint(&raw[i..end], "variable"));
+                while chars.peek().is_some_and(|&(j, _)| j < end) {
+                    chars.next();
+                }
+            }
+            _ => result.push(ch),
+        }
+    }
+
+    result
+}
+
+// 双引号括起的部分按字符串上色，其中没有转义的$VAR按变量上色
+fn highlight_double_quoted(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut result = String::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'$' => {
+                let len = text[i + 1..]
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(text.len() - i - 1);
+                if len == 0 {
+                    i += 1;
+                    continue;
+                }
+                if start < i {
+                    result.push_str(&paint(&text[start..i], "string"));
+                }
+                result.push_str(&paint(&text[i..i + 1 + len], "variable"));
+                i += 1 + len;
+                start = i;
+            }
+            _ => i += 1,
+        }
+    }
+    if start < text.len() {
+        result.push_str(&paint(&text[start..], "string"));
+    }
+    result
+}
+
+// 使用和parser相同的tokenizer为一行命令上色，command_element决定命令名的颜色
+pub fn highlight_command(line: &str, command_element: &dyn Fn(&str) -> &'static str) -> String {
+    let mut result = String::new();
+    let mut last_end = 0;
+    let mut expect_command = true;
+
+    for token in tokenize(line) {
+        // token之间的空白原样保留
+        result.push_str(&line[last_end..token.start]);
+        let raw = &line[token.start..token.end];
+
+        match token.kind {
+            TokenKind::Pipe | TokenKind::Background => {
+                result.push_str(&paint(raw, "operator"));
+                expect_command = true;
+            }
+            TokenKind::Word if expect_command => {
+                result.push_str(&paint(raw, command_element(&token.text)));
+                expect_command = false;
+            }
+            TokenKind::Word if raw == ">" || raw == "<" => {
+                result.push_str(&paint(raw, "redirection"));
+            }
+            TokenKind::Word => result.push_str(&highlight_argument(raw)),
+        }
+
+        last_end = token.end;
+    }
+    result.push_str(&line[last_end..]);
+    result
+}
+
+impl Highlighter for PshHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        let right_prompt = self.right_prompt().as_ref().and_then(|rp| rp.render(line, pos));
+        if line.is_empty() || !color_enabled() {
+            return match right_prompt {
+                Some(right_prompt) => Cow::Owned(format!("{}{}", line, right_prompt)),
+                None => Cow::Borrowed(line),
+            };
+        }
+
+        let mut result = highlight_command(line, &|name| self.command_element(name));
+        result.push_str(&right_prompt.unwrap_or_default());
+
+        Cow::Owned(result)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        hint::highlight_hint(hint)
+    }
+
+    // 每次输入都需要重新上色，只有移动光标时不需要
+    fn highlight_char(&self, _line: &str, _pos: usize, kind: CmdKind) -> bool {
+        kind != CmdKind::MoveCursor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlights_variables_in_double_quotes() {
+        let string = |text: &str| paint(text, "string");
+        let variable = |text: &str| paint(text, "variable");
+        assert_eq!(highlight_argument("\"hi $USER!\""), format!("{}{}{}", string("\"hi "), variable("$USER"), string("!\"")));
+        assert_eq!(highlight_argument("\"$A$B\""), format!("{}{}{}{}", string("\""), variable("$A"), variable("$B"), string("\"")));
+        // 转义的$、单引号内的$和单独的$都不是变量
+        assert_eq!(highlight_argument("\"\\$HOME $\""), string("\"\\$HOME $\""));
+        assert_eq!(highlight_argument("'$HOME'"), string("'$HOME'"));
+        assert_eq!(highlight_argument("x=$HOME/\"é$X"), format!("x={}/{}{}", variable("$HOME"), string("\"é"), variable("$X")));
+    }
+}
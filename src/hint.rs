@@ -0,0 +1,42 @@
+use std::borrow::Cow;
+use rustyline::hint::Hinter;
+use rustyline::{Cmd, ConditionalEventHandler, Context, Event, EventContext, RepeatCount};
+
+use crate::completion::PshHelper;
+use crate::history;
+use crate::prompt::{color_code, color_enabled};
+use crate::theme;
+
+impl Hinter for PshHelper {
+    type Hint = String;
+
+    // 光标在行尾时，根据历史记录给出fish风格的自动建议
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        history::suggest(line)
+    }
+}
+
+// 用主题的hint颜色（默认为灰色）显示自动建议
+pub fn highlight_hint(hint: &str) -> Cow<'_, str> {
+    if !color_enabled() {
+        return Cow::Borrowed(hint);
+    }
+    let (r, g, b) = theme::with_current(|theme| theme.highlight_color("hint"));
+    Cow::Owned(format!("{}{}\x1b[0m", color_code(r, g, b), hint))
+}
+
+// Ctrl+F：有自动建议且光标在行尾时接受建议，否则保持默认的光标右移
+pub struct AcceptHint;
+
+impl ConditionalEventHandler for AcceptHint {
+    fn handle(&self, _evt: &Event, _n: RepeatCount, _positive: bool, ctx: &EventContext) -> Option<Cmd> {
+        if ctx.has_hint() && ctx.pos() == ctx.line().len() {
+            Some(Cmd::CompleteHint)
+        } else {
+            None
+        }
+    }
+}
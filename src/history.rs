@@ -0,0 +1,298 @@
+use std::env;
+use std::path::PathBuf;
+use std::sync::{LazyLock, Mutex};
+use crate::error::ShellError;
+
+// 一条历史记录
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub line: String,
+    // 执行这条命令时的工作目录
+    pub cwd: PathBuf,
+    // 退出状态，命令还没执行完时为None
+    pub status: Option<i32>,
+}
+
+// psh自己维护的历史记录
+// rustyline的历史只存在于Editor内部，builtin拿不到，所以这里保存一份供history命令和!展开使用
+struct History {
+    entries: Vec<HistoryEntry>,
+    // 被builtin修改（清空/删除）后置为true，main_loop据此同步rustyline的历史
+    changed: bool,
+}
+
+static HISTORY: LazyLock<Mutex<History>> = LazyLock::new(|| Mutex::new(History {
+    entries: Vec::new(),
+    changed: false,
+}));
+
+/// 添加一条历史记录，忽略空行和与上一条重复的命令（与rustyline默认行为一致）
+/// 返回这条命令对应的记录的下标，供set_status使用
+pub fn push(line: &str) -> Option<usize> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    let mut history = HISTORY.lock().unwrap();
+    if history.entries.last().is_none_or(|e| e.line != line) {
+        history.entries.push(HistoryEntry {
+            line: line.to_string(),
+            cwd: env::current_dir().unwrap_or_default(),
+            status: None,
+        });
+    }
+    Some(history.entries.len() - 1)
+}
+
+/// 记录push返回的那条命令的退出状态
+/// 命令执行期间可能添加了其他记录（如ask执行的命令），所以不能直接修改最后一条
+/// 记录已经被history -c / -d删除时忽略
+pub fn set_status(index: usize, status: i32) {
+    if let Some(entry) = HISTORY.lock().unwrap().entries.get_mut(index) {
+        entry.status = Some(status);
+    }
+}
+
+/// 为输入的前缀寻找自动建议，返回需要补在后面的部分
+/// 优先选择在当前目录执行过的命令，跳过执行失败的命令
+pub fn suggest(prefix: &str) -> Option<String> {
+    if prefix.trim().is_empty() {
+        return None;
+    }
+
+    let cwd = env::current_dir().unwrap_or_default();
+    let history = HISTORY.lock().unwrap();
+    let candidates = || history.entries.iter().rev()
+        .filter(|e| e.line.len() > prefix.len() && e.line.starts_with(prefix))
+        .filter(|e| e.status.is_none_or(|status| status == 0));
+
+    candidates().find(|e| e.cwd == cwd)
+        .or_else(|| candidates().next())
+        .map(|e| e.line[prefix.len()..].to_string())
+}
+
+/// 返回全部历史记录的拷贝，编号从1开始对应下标+1
+pub fn entries() -> Vec<HistoryEntry> {
+    HISTORY.lock().unwrap().entries.clone()
+}
+
+pub fn clear() {
+    let mut history = HISTORY.lock().unwrap();
+    history.entries.clear();
+    history.changed = true;
+}
+
+/// 删除第n条历史记录（从1开始编号）
+pub fn delete(n: usize) -> Result<(), ShellError> {
+    let mut history = HISTORY.lock().unwrap();
+    if n == 0 || n > history.entries.len() {
+        return Err(ShellError::HistoryError(format!("{}: history position out of range", n)));
+    }
+    history.entries.remove(n - 1);
+    history.changed = true;
+
+    Ok(())
+}
+
+/// 标记历史记录需要同步到rustyline，用于builtin自己添加了历史的情况
+pub fn mark_changed() {
+    HISTORY.lock().unwrap().changed = true;
+}
+
+/// 如果历史记录被builtin修改过，返回true并重置标记
+pub fn take_changed() -> bool {
+    let mut history = HISTORY.lock().unwrap();
+    std::mem::replace(&mut history.changed, false)
+}
+
+/// 对一行输入做csh风格的历史展开，在parse之前调用
+/// 支持 !! !$ !n !-n !prefix 以及行首的 ^old^new
+/// 没有发生展开时返回Ok(None)
+pub fn expand(line: &str) -> Result<Option<String>, ShellError> {
+    expand_with(line, &HISTORY.lock().unwrap().entries)
+}
+
+// 结束!prefix的字符，!后面直接跟这些字符时!按普通字符处理
+const EVENT_DELIMITERS: &str = ";|&<>\"'";
+
+fn expand_with(line: &str, entries: &[HistoryEntry]) -> Result<Option<String>, ShellError> {
+    // ^old^new 快速替换，只作用于上一条命令
+    if let Some(rest) = line.strip_prefix('^') {
+        let mut parts = rest.splitn(3, '^');
+        let old = parts.next().unwrap_or("");
+        let new = parts.next().unwrap_or("");
+        let tail = parts.next().unwrap_or("");
+
+        let last = entries.last()
+            .ok_or_else(|| ShellError::HistoryError("^: event not found".to_string()))?;
+        if old.is_empty() || !last.line.contains(old) {
+            return Err(ShellError::HistoryError(format!("^{}^{}: substitution failed", old, new)));
+        }
+        return Ok(Some(last.line.replacen(old, new, 1) + tail));
+    }
+
+    let chars: Vec<char> = line.chars().collect();
+    let mut result = String::new();
+    let mut in_single_quote = false;
+    let mut expanded = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let ch = chars[i];
+
+        // 单引号内和反斜杠转义的!不展开
+        if ch == '\'' {
+            in_single_quote = !in_single_quote;
+        } else if ch == '\\' && chars.get(i + 1) == Some(&'!') {
+            result.push('!');
+            i += 2;
+            continue;
+        }
+
+        if ch != '!' || in_single_quote {
+            result.push(ch);
+            i += 1;
+            continue;
+        }
+
+        // !后面跟空白、=、(、引号、管道和重定向等符号或行尾时按普通字符处理，如 echo "hi!"
+        let next = match chars.get(i + 1) {
+            Some(c) if !c.is_whitespace() && *c != '=' && *c != '(' && !EVENT_DELIMITERS.contains(*c) => *c,
+            _ => {
+                result.push(ch);
+                i += 1;
+                continue;
+            }
+        };
+
+        let (replacement, consumed) = match next {
+            '!' => (last_event(entries, "!!")?.to_string(), 2),
+            '$' => {
+                let last = last_event(entries, "!$")?;
+                (last.split_whitespace().last().unwrap_or("").to_string(), 2)
+            }
+            '-' | '0'..='9' => {
+                // !n 或 !-n
+                let start = if next == '-' { i + 2 } else { i + 1 };
+                let end = (start..chars.len()).find(|&j| !chars[j].is_ascii_digit()).unwrap_or(chars.len());
+                let digits: String = chars[start..end].iter().collect();
+                let event: String = chars[i..end].iter().collect();
+
+                let n: usize = digits.parse()
+                    .map_err(|_| ShellError::HistoryError(format!("{}: event not found", event)))?;
+                let index = if next == '-' {
+                    entries.len().checked_sub(n)
+                } else {
+                    n.checked_sub(1)
+                };
+                let entry = index
+                    .and_then(|idx| entries.get(idx))
+                    .filter(|_| n > 0)
+                    .ok_or_else(|| ShellError::HistoryError(format!("{}: event not found", event)))?;
+                (entry.line.clone(), end - i)
+            }
+            _ => {
+                // !prefix：查找最近一条以prefix开头的命令
+                let end = (i + 1..chars.len())
+                    .find(|&j| chars[j].is_whitespace() || EVENT_DELIMITERS.contains(chars[j]))
+                    .unwrap_or(chars.len());
+                let prefix: String = chars[i + 1..end].iter().collect();
+                let entry = entries.iter().rev()
+                    .find(|e| e.line.starts_with(&prefix))
+                    .ok_or_else(|| ShellError::HistoryError(format!("!{}: event not found", prefix)))?;
+                (entry.line.clone(), end - i)
+            }
+        };
+
+        result.push_str(&replacement);
+        expanded = true;
+        i += consumed;
+    }
+
+    Ok(if expanded { Some(result) } else { None })
+}
+
+fn last_event<'a>(entries: &'a [HistoryEntry], event: &str) -> Result<&'a str, ShellError> {
+    entries.last()
+        .map(|e| e.line.as_str())
+        .ok_or_else(|| ShellError::HistoryError(format!("{}: event not found", event)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(lines: &[&str]) -> Vec<HistoryEntry> {
+        lines.iter()
+            .map(|line| HistoryEntry { line: line.to_string(), cwd: PathBuf::new(), status: Some(0) })
+            .collect()
+    }
+
+    fn expand_in(line: &str, lines: &[&str]) -> Option<String> {
+        expand_with(line, &history(lines)).unwrap()
+    }
+
+    #[test]
+    fn expands_last_command_and_argument() {
+        let lines = ["ls -l /tmp", "echo one two"];
+        assert_eq!(expand_in("!!", &lines).as_deref(), Some("echo one two"));
+        assert_eq!(expand_in("sudo !!", &lines).as_deref(), Some("sudo echo one two"));
+        assert_eq!(expand_in("cat !$", &lines).as_deref(), Some("cat two"));
+    }
+
+    #[test]
+    fn expands_numbered_events() {
+        let lines = ["first", "second", "third"];
+        assert_eq!(expand_in("!1", &lines).as_deref(), Some("first"));
+        assert_eq!(expand_in("!-2", &lines).as_deref(), Some("second"));
+        assert_eq!(expand_in("!3 x", &lines).as_deref(), Some("third x"));
+        assert!(expand_with("!4", &history(&lines)).is_err());
+        assert!(expand_with("!0", &history(&lines)).is_err());
+        assert!(expand_with("!-4", &history(&lines)).is_err());
+    }
+
+    #[test]
+    fn expands_prefix() {
+        let lines = ["git status", "ls", "git log"];
+        assert_eq!(expand_in("!git", &lines).as_deref(), Some("git log"));
+        assert_eq!(expand_in("!l|wc", &lines).as_deref(), Some("ls|wc"));
+        assert!(expand_with("!cargo", &history(&lines)).is_err());
+    }
+
+    #[test]
+    fn substitutes_in_last_command() {
+        let lines = ["cat foo.txt"];
+        assert_eq!(expand_in("^foo^bar", &lines).as_deref(), Some("cat bar.txt"));
+        assert_eq!(expand_in("^foo^bar^ -n", &lines).as_deref(), Some("cat bar.txt -n"));
+        assert!(expand_with("^baz^bar", &history(&lines)).is_err());
+        assert!(expand_with("^foo^bar", &[]).is_err());
+    }
+
+    #[test]
+    fn leaves_literal_bang() {
+        let lines = ["echo one"];
+        for line in ["echo 'hi!!'", "echo \\!!", "echo hi!", "echo ! x", "a!=b", "echo \"hi!\"", "echo wow!|cat", "x!;y", "x!>f", "x!&"] {
+            assert_eq!(expand_in(line, &lines), None, "{}", line);
+            // 没有历史时也不应报错
+            assert_eq!(expand_in(line, &[]), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn sets_status_of_pushed_entry() {
+        // 只有这个测试使用全局的历史记录
+        let outer = push("ask list files").unwrap();
+        let inner = push("false").unwrap();
+        set_status(inner, 1);
+        set_status(outer, 0);
+        assert_eq!(push("false"), Some(inner));
+        let entries = entries();
+        assert_eq!(entries[outer].status, Some(0));
+        assert_eq!(entries[inner].status, Some(1));
+        assert_eq!(push("  "), None);
+        // 失败的命令不作为自动建议
+        assert_eq!(suggest("ask l").as_deref(), Some("ist files"));
+        assert_eq!(suggest("fal"), None);
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+use rustyline::config::{Configurer, EditMode};
+use rustyline::{Cmd, ConditionalEventHandler, Event, EventContext, EventHandler, KeyCode, KeyEvent, Modifiers, Movement, RepeatCount};
+
+use crate::completion::PshEditor;
+use crate::error::ShellError;
+use crate::hint::AcceptHint;
+use crate::history;
+
+// bind可以使用的动作：(名字, 说明)
+pub const ACTIONS: &[(&str, &str)] = &[
+    ("accept-suggestion", "accept the autosuggestion when the cursor is at the end of the line"),
+    ("accept-line", "run the current line"),
+    ("beginning-of-line", "move to the start of the line"),
+    ("clear-screen", "clear the screen and redraw the prompt"),
+    ("complete", "complete the word before the cursor"),
+    ("end-of-line", "move to the end of the line"),
+    ("history-prefix-search", "search backward for history entries starting with the line"),
+    ("history-search", "incremental reverse history search"),
+    ("history-search-forward", "incremental forward history search"),
+    ("insert-last-argument", "insert the last argument of the previous command"),
+    ("kill-line", "delete from the cursor to the end of the line"),
+    ("undo", "undo the last edit"),
+];
+
+// 编辑模式和自定义按键绑定
+// builtin只修改这里的状态，main_loop在读取下一行之前调用apply同步到Editor
+struct KeyBindings {
+    mode: EditMode,
+    // 按键描述 -> (按键, 动作名)
+    bindings: BTreeMap<String, (KeyEvent, String)>,
+    // 被移除、需要从Editor解绑的按键
+    removed: Vec<KeyEvent>,
+    changed: bool,
+}
+
+static KEY_BINDINGS: LazyLock<Mutex<KeyBindings>> = LazyLock::new(|| {
+    // 默认绑定：Ctrl+F接受自动建议，Alt+.插入上一条命令的最后一个参数
+    let mut bindings = BTreeMap::new();
+    bindings.insert("C-f".to_string(), (KeyEvent::ctrl('F'), "accept-suggestion".to_string()));
+    bindings.insert("M-.".to_string(), (KeyEvent::alt('.'), "insert-last-argument".to_string()));
+
+    Mutex::new(KeyBindings {
+        mode: EditMode::Emacs,
+        bindings,
+        removed: Vec::new(),
+        changed: true,
+    })
+});
+
+// 插入上一条命令的最后一个参数
+struct InsertLastArgument;
+
+impl ConditionalEventHandler for InsertLastArgument {
+    fn handle(&self, _evt: &Event, _n: RepeatCount, _positive: bool, _ctx: &EventContext) -> Option<Cmd> {
+        let entries = history::entries();
+        let last_argument = entries.last()?.line.split_whitespace().last()?.to_string();
+        Some(Cmd::Insert(1, last_argument))
+    }
+}
+
+fn action_handler(action: &str) -> Option<EventHandler> {
+    let handler = match action {
+        "accept-suggestion" => EventHandler::Conditional(Box::new(AcceptHint)),
+        "accept-line" => EventHandler::Simple(Cmd::AcceptLine),
+        "beginning-of-line" => EventHandler::Simple(Cmd::Move(Movement::BeginningOfLine)),
+        "clear-screen" => EventHandler::Simple(Cmd::ClearScreen),
+        "complete" => EventHandler::Simple(Cmd::Complete),
+        "end-of-line" => EventHandler::Simple(Cmd::Move(Movement::EndOfLine)),
+        "history-prefix-search" => EventHandler::Simple(Cmd::HistorySearchBackward),
+        "history-search" => EventHandler::Simple(Cmd::ReverseSearchHistory),
+        "history-search-forward" => EventHandler::Simple(Cmd::ForwardSearchHistory),
+        "insert-last-argument" => EventHandler::Conditional(Box::new(InsertLastArgument)),
+        "kill-line" => EventHandler::Simple(Cmd::Kill(Movement::EndOfLine)),
+        "undo" => EventHandler::Simple(Cmd::Undo(1)),
+        _ => return None,
+    };
+    Some(handler)
+}
+
+/// 解析按键描述，如 C-l、M-.、C-M-x、Tab、Up、F5
+/// 兼容bash的写法 "\C-l": action
+pub fn parse_key(spec: &str) -> Result<(String, KeyEvent), ShellError> {
+    let invalid = || ShellError::BuiltinError(format!("bind: {}: invalid key", spec));
+
+    let mut rest = spec.trim_end_matches(':');
+    let mut modifiers = Modifiers::NONE;
+    let mut normalized = String::new();
+    loop {
+        rest = rest.trim_start_matches('\\');
+        if let Some(r) = rest.strip_prefix("C-") {
+            modifiers |= Modifiers::CTRL;
+            normalized.push_str("C-");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("M-") {
+            modifiers |= Modifiers::ALT;
+            normalized.push_str("M-");
+            rest = r;
+        } else {
+            break;
+        }
+    }
+
+    let code = match rest.to_ascii_lowercase().as_str() {
+        "tab" => KeyCode::Tab,
+        "enter" | "return" => KeyCode::Enter,
+        "esc" | "escape" => KeyCode::Esc,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "delete" => KeyCode::Delete,
+        "backspace" => KeyCode::Backspace,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        name if name.len() > 1 && name.starts_with('f') => {
+            let n: u8 = name[1..].parse().map_err(|_| invalid())?;
+            KeyCode::F(n)
+        }
+        _ => {
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => {
+                    // Ctrl组合统一用小写描述，与KeyEvent::new的规范化一致
+                    let c = if modifiers.contains(Modifiers::CTRL) { c.to_ascii_lowercase() } else { c };
+                    normalized.push(c);
+                    return Ok((normalized, KeyEvent::new(c, modifiers)));
+                }
+                _ => return Err(invalid()),
+            }
+        }
+    };
+
+    normalized.push_str(&rest.to_ascii_lowercase());
+    Ok((normalized, KeyEvent(code, modifiers)))
+}
+
+pub fn set_mode(mode: EditMode) {
+    let mut state = KEY_BINDINGS.lock().unwrap();
+    state.mode = mode;
+    state.changed = true;
+}
+
+pub fn mode() -> EditMode {
+    KEY_BINDINGS.lock().unwrap().mode
+}
+
+pub fn bind(spec: &str, action: &str) -> Result<(), ShellError> {
+    if action_handler(action).is_none() {
+        return Err(ShellError::BuiltinError(format!("bind: {}: unknown action (see bind -l)", action)));
+    }
+    let (name, key) = parse_key(spec)?;
+
+    let mut state = KEY_BINDINGS.lock().unwrap();
+    state.bindings.insert(name, (key, action.to_string()));
+    state.changed = true;
+
+    Ok(())
+}
+
+pub fn unbind(spec: &str) -> Result<(), ShellError> {
+    let (name, _) = parse_key(spec)?;
+
+    let mut state = KEY_BINDINGS.lock().unwrap();
+    let (key, _) = state.bindings.remove(&name)
+        .ok_or_else(|| ShellError::BuiltinError(format!("bind: {}: key is not bound", spec)))?;
+    state.removed.push(key);
+    state.changed = true;
+
+    Ok(())
+}
+
+/// 返回当前的按键绑定：(按键描述, 动作名)
+pub fn bindings() -> Vec<(String, String)> {
+    KEY_BINDINGS.lock().unwrap().bindings.iter()
+        .map(|(name, (_, action))| (name.clone(), action.clone()))
+        .collect()
+}
+
+/// 把编辑模式和按键绑定的修改应用到Editor
+pub fn apply(reader: &mut PshEditor) {
+    let mut state = KEY_BINDINGS.lock().unwrap();
+    if !state.changed {
+        return;
+    }
+
+    reader.set_edit_mode(state.mode);
+    for key in state.removed.drain(..) {
+        reader.unbind_sequence(key);
+    }
+    for (key, action) in state.bindings.values() {
+        if let Some(handler) = action_handler(action) {
+            reader.bind_sequence(*key, handler);
+        }
+    }
+    state.changed = false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(spec: &str) -> (String, KeyEvent) {
+        parse_key(spec).unwrap_or_else(|e| panic!("{}: {}", spec, e))
+    }
+
+    #[test]
+    fn parses_modifiers() {
+        assert_eq!(key("C-l"), ("C-l".to_string(), KeyEvent::new('l', Modifiers::CTRL)));
+        assert_eq!(key("C-L"), ("C-l".to_string(), KeyEvent::new('l', Modifiers::CTRL)));
+        assert_eq!(key("M-."), ("M-.".to_string(), KeyEvent::new('.', Modifiers::ALT)));
+        assert_eq!(key("C-M-x"), ("C-M-x".to_string(), KeyEvent::new('x', Modifiers::CTRL_ALT)));
+        // bash的写法
+        assert_eq!(key("\\C-l:"), key("C-l"));
+        assert_eq!(key("\\M-\\C-x"), ("M-C-x".to_string(), KeyEvent::new('x', Modifiers::CTRL_ALT)));
+        // 没有Ctrl时保留大小写
+        assert_eq!(key("M-X").1, KeyEvent::new('X', Modifiers::ALT));
+    }
+
+    #[test]
+    fn parses_named_keys() {
+        assert_eq!(key("Tab"), ("tab".to_string(), KeyEvent(KeyCode::Tab, Modifiers::NONE)));
+        assert_eq!(key("C-Up"), ("C-up".to_string(), KeyEvent(KeyCode::Up, Modifiers::CTRL)));
+        assert_eq!(key("return").1, KeyEvent(KeyCode::Enter, Modifiers::NONE));
+        assert_eq!(key("PageDown").1, KeyEvent(KeyCode::PageDown, Modifiers::NONE));
+        assert_eq!(key("F5"), ("f5".to_string(), KeyEvent(KeyCode::F(5), Modifiers::NONE)));
+        assert_eq!(key("M-F12").1, KeyEvent(KeyCode::F(12), Modifiers::ALT));
+        // 单独的f是普通字符
+        assert_eq!(key("f").1, KeyEvent::new('f', Modifiers::NONE));
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        for spec in ["", "C-", "M-", "xy", "Fx", "F999", "C-tabby", "Super-x"] {
+            assert!(parse_key(spec).is_err(), "{}", spec);
+        }
+    }
+}
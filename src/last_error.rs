@@ -0,0 +1,85 @@
+use std::env;
+use std::io::{self, Read, Write};
+use std::process::ChildStderr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+// 最近一次失败的命令，供why和chat --last-error使用
+#[derive(Debug, Clone)]
+pub struct LastError {
+    pub line: String,
+    pub status: i32,
+    // 标准错误输出的末尾部分
+    pub stderr: String,
+}
+
+// 保留的标准错误输出的默认字节数，可以通过PSH_STDERR_TAIL修改
+const DEFAULT_TAIL_SIZE: usize = 4096;
+
+static LAST_ERROR: Mutex<Option<LastError>> = Mutex::new(None);
+// 当前命令的标准错误输出，每条命令开始前清空
+static STDERR_TAIL: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+
+// 执行模型请求的命令时临时开启捕获
+static FORCE_CAPTURE: AtomicBool = AtomicBool::new(false);
+
+// 是否通过tee捕获外部命令的标准错误输出，设置PSH_CAPTURE_STDERR=1开启
+pub fn capture_enabled() -> bool {
+    FORCE_CAPTURE.load(Ordering::SeqCst) || env::var("PSH_CAPTURE_STDERR").is_ok_and(|v| matches!(v.as_str(), "1" | "on" | "true" | "yes"))
+}
+
+fn tail_size() -> usize {
+    env::var("PSH_STDERR_TAIL").ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_TAIL_SIZE)
+}
+
+pub fn clear_stderr() {
+    STDERR_TAIL.lock().unwrap().clear();
+}
+
+// 记录一段标准错误输出，只保留末尾的tail_size个字节
+pub fn record_stderr(bytes: &[u8]) {
+    let mut tail = STDERR_TAIL.lock().unwrap();
+    tail.extend_from_slice(bytes);
+    let excess = tail.len().saturating_sub(tail_size());
+    tail.drain(..excess);
+}
+
+// 把子进程的标准错误输出原样转发到psh的标准错误，同时记录下来
+pub fn tee(mut stderr: ChildStderr) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buffer = [0u8; 4096];
+        while let Ok(n) = stderr.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+            let _ = io::stderr().write_all(&buffer[..n]);
+            record_stderr(&buffer[..n]);
+        }
+    })
+}
+
+// 执行f并返回它期间的标准错误输出（末尾的tail_size个字节）
+pub fn capture_stderr<T>(f: impl FnOnce() -> T) -> (T, String) {
+    FORCE_CAPTURE.store(true, Ordering::SeqCst);
+    clear_stderr();
+    let result = f();
+    let stderr = String::from_utf8_lossy(&STDERR_TAIL.lock().unwrap()).into_owned();
+    FORCE_CAPTURE.store(false, Ordering::SeqCst);
+    (result, stderr)
+}
+
+// 命令执行完后调用，失败时连同捕获的标准错误输出一起保存
+pub fn finish(line: &str, status: i32) {
+    if status == 0 {
+        return;
+    }
+    let stderr = String::from_utf8_lossy(&STDERR_TAIL.lock().unwrap()).into_owned();
+    *LAST_ERROR.lock().unwrap() = Some(LastError { line: line.to_string(), status, stderr });
+}
+
+pub fn get() -> Option<LastError> {
+    LAST_ERROR.lock().unwrap().clone()
+}
@@ -1,6 +1,6 @@
-use rustyline::DefaultEditor;
-use dotenvy::dotenv;
-use owo_colors::OwoColorize;
+use std::env;
+use std::process::exit;
+use rustyline::config::{CompletionType, Config};
 
 mod parser;
 mod builtins;
@@ -8,35 +8,79 @@ mod executor;
 mod run;
 mod error;
 mod model_call;
+mod provider;
+mod tools;
+mod markdown;
 mod prompt;
 mod args_analysis;
+mod history;
+mod completion;
+mod highlight;
+mod hint;
+mod keybind;
+mod config;
+mod theme;
+mod git;
+mod banner;
+mod chat;
+mod last_error;
 
-fn main() {
-    // Banner
-    let banner = r#"
-  _____      _            _____ _          _ _
- |  __ \    | |          / ____| |        | | |
- | |__) |_ _| |_ __ ___ | (___ | |__   ___| | |
- |  ___/ _` | | '_ ` _ \ \___ \| '_ \ / _ \ | |
- | |  | (_| | | | | | | |____) | | | |  __/ | |
- |_|   \__,_|_|_| |_| |_|_____/|_| |_|\___|_|_|
-
-             Welcome to PalmShell!
-
-"#;
-    // 用渐变色打印Banner
-    for (i, line) in banner.lines().enumerate() {
-        let r = (i * 30) as u8;
-        let g = 0;
-        let b = 255;
-        println!("{}", line.truecolor(r, g, b).bold());
+use completion::{PshEditor, PshHelper};
+use prompt::ColorChoice;
+
+const USAGE: &str = "usage: psh [-l|--login] [--color=never|auto|always]";
+
+// 解析命令行参数，返回是否为登录shell（-l、--login或者argv[0]以-开头）
+fn parse_args() -> bool {
+    let mut args = env::args();
+    let mut login = args.next().is_some_and(|arg0| arg0.starts_with('-'));
+
+    while let Some(arg) = args.next() {
+        if arg == "-l" || arg == "--login" {
+            login = true;
+            continue;
+        }
+
+        let value = match arg.strip_prefix("--color") {
+            Some("") => args.next(),
+            Some(value) if value.starts_with('=') => Some(value[1..].to_string()),
+            _ => {
+                error::report(format!("unknown option '{}'", arg));
+                eprintln!("{}", USAGE);
+                exit(2);
+            }
+        };
+        match value.as_deref().and_then(ColorChoice::parse) {
+            Some(choice) => prompt::set_color_choice(choice),
+            None => {
+                error::report("--color must be one of never, auto, always");
+                exit(2);
+            }
+        }
     }
 
+    login
+}
+
+fn main() {
+    let login = parse_args();
+    // 只读取用户的配置文件，不读取当前目录的.env，避免克隆的项目改写API地址等设置
+    config::load();
+
+    banner::show(login);
+
     // 初始化Readline
-    let reader =  DefaultEditor::new().unwrap();
-    dotenv().ok();
+    let config = Config::builder()
+        .completion_type(CompletionType::List)
+        .build();
+    let mut reader = PshEditor::with_config(config).unwrap();
+    reader.set_helper(Some(PshHelper::new()));
 
-    //fs::create_dir_all("chats").unwrap();
+    // 执行rc文件，可以在其中设置编辑模式和按键绑定
+    let rc_path = config::rc_path();
+    if rc_path.is_file() {
+        run::source_file(&rc_path);
+    }
 
     run::main_loop(reader);
 }
@@ -0,0 +1,390 @@
+use std::io::{self, Write};
+
+use crate::highlight::{self, highlight_command};
+use crate::parser::BUILTINS;
+use crate::prompt::{color_enabled, gradient_text, visible_width};
+use crate::run;
+use crate::theme;
+
+// 代码块边框和分割线的最大宽度
+const MAX_WIDTH: usize = 80;
+
+// 列表的各级符号
+const BULLETS: [&str; 3] = ["•", "◦", "▪"];
+
+// 代码高亮使用的关键字，不区分语言
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "case", "catch", "class", "const", "continue", "def", "default", "defer",
+    "do", "elif", "else", "enum", "except", "export", "extends", "false", "finally", "fn", "for", "from", "func",
+    "function", "go", "if", "impl", "import", "in", "interface", "let", "loop", "match", "mod", "mut", "new",
+    "nil", "None", "null", "package", "pass", "pub", "raise", "return", "self", "Self", "static", "struct",
+    "switch", "then", "this", "throw", "trait", "True", "true", "False", "try", "type", "use", "var", "void",
+    "where", "while", "with", "yield", "fi", "done", "esac",
+];
+
+// 按行把模型回复中的Markdown渲染为终端文本，可以边接收边渲染
+// 标题、列表、引用、分割线、粗体、斜体、行内代码、链接，代码块加上边框并做简单的语法高亮
+pub struct Renderer {
+    // 还没有收到换行的部分
+    line: String,
+    // 在代码块中时为代码块的语言和开始标记（```或~~~）
+    code: Option<(String, String)>,
+    width: usize,
+}
+
+impl Renderer {
+    pub fn new() -> Self {
+        Renderer {
+            line: String::new(),
+            code: None,
+            width: run::terminal_columns().unwrap_or(MAX_WIDTH).min(MAX_WIDTH),
+        }
+    }
+
+    // 输出text中所有完整的行，最后不完整的一行留到下次
+    pub fn push(&mut self, text: &str, out: &mut dyn Write) -> io::Result<()> {
+        self.line.push_str(text);
+        while let Some(newline) = self.line.find('\n') {
+            let line: String = self.line.drain(..=newline).collect();
+            let rendered = self.render_line(line.trim_end_matches(['\n', '\r']));
+            writeln!(out, "{}", rendered)?;
+        }
+        out.flush()
+    }
+
+    // 输出剩下的内容，没有闭合的代码块也补上边框
+    pub fn finish(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            let rendered = self.render_line(&line);
+            writeln!(out, "{}", rendered)?;
+        }
+        if self.code.take().is_some() {
+            writeln!(out, "{}", self.bottom_border())?;
+        }
+        out.flush()
+    }
+
+    fn render_line(&mut self, line: &str) -> String {
+        let trimmed = line.trim_start();
+
+        if let Some((lang, fence)) = &self.code {
+            if trimmed.starts_with(fence.as_str()) && trimmed.trim_end() == fence {
+                self.code = None;
+                return self.bottom_border();
+            }
+            return format!("{} {}", paint("│", "hint"), highlight_code(line, lang));
+        }
+
+        for fence in ["```", "~~~"] {
+            if let Some(lang) = trimmed.strip_prefix(fence) {
+                let lang = lang.trim().to_string();
+                let top = self.top_border(&lang);
+                self.code = Some((lang, fence.to_string()));
+                return top;
+            }
+        }
+
+        // 标题：去掉#号，一二级标题使用渐变色
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        if (1..=6).contains(&level) && trimmed[level..].starts_with(' ') {
+            let text = strip_inline(trimmed[level..].trim());
+            let text = if level <= 2 {
+                theme::with_current(|theme| gradient_text(&text, &theme.gradient("default"), false))
+            } else {
+                paint(&text, "builtin")
+            };
+            return format!("{}{}{}", style("\x1b[1m"), text, style("\x1b[0m"));
+        }
+
+        // 分割线
+        let compact: String = trimmed.chars().filter(|c| !c.is_whitespace()).collect();
+        if compact.len() >= 3 && ['-', '*', '_'].iter().any(|&marker| compact.chars().all(|c| c == marker)) {
+            return paint(&"─".repeat(self.width), "hint");
+        }
+
+        // 引用
+        if let Some(quote) = trimmed.strip_prefix('>') {
+            return format!("{} {}", paint("│", "hint"), render_inline(quote.trim_start()));
+        }
+
+        // 列表，缩进每两个空格为一级
+        let indent = &line[..line.len() - trimmed.len()];
+        let depth = indent.replace('\t', "  ").len() / 2;
+        if let Some(item) = ["- ", "* ", "+ "].iter().find_map(|marker| trimmed.strip_prefix(marker)) {
+            let (marker, item) = if let Some(item) = item.strip_prefix("[ ] ") {
+                ("☐", item)
+            } else if let Some(item) = item.strip_prefix("[x] ").or_else(|| item.strip_prefix("[X] ")) {
+                ("☑", item)
+            } else {
+                (BULLETS[depth % BULLETS.len()], item)
+            };
+            return format!("{}{} {}", indent, paint(marker, "operator"), render_inline(item));
+        }
+        let digits = trimmed.chars().take_while(char::is_ascii_digit).count();
+        if digits > 0
+            && let Some(item) = trimmed[digits..].strip_prefix(". ").or_else(|| trimmed[digits..].strip_prefix(") "))
+        {
+            return format!("{}{} {}", indent, paint(&trimmed[..digits + 1], "operator"), render_inline(item));
+        }
+
+        render_inline(line)
+    }
+
+    fn top_border(&self, lang: &str) -> String {
+        let label = if lang.is_empty() { String::new() } else { format!(" {} ", lang) };
+        let rest = self.width.saturating_sub(2 + visible_width(&label));
+        paint(&format!("╭─{}{}", label, "─".repeat(rest)), "hint")
+    }
+
+    fn bottom_border(&self) -> String {
+        paint(&format!("╰{}", "─".repeat(self.width.saturating_sub(1))), "hint")
+    }
+}
+
+// 不支持颜色时不输出转义序列
+fn style(code: &str) -> &str {
+    if color_enabled() { code } else { "" }
+}
+
+fn paint(text: &str, element: &str) -> String {
+    if color_enabled() { highlight::paint(text, element) } else { text.to_string() }
+}
+
+// 去掉行内标记，用于标题等整体上色的文本
+fn strip_inline(text: &str) -> String {
+    text.replace("**", "").replace("__", "").replace('`', "")
+}
+
+// 行内格式：`代码`、**粗体**、*斜体*、_斜体_、[文字](链接)和\转义
+fn render_inline(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut result = String::new();
+    let mut bold = false;
+    let mut italic = false;
+    let mut i = 0;
+
+    // 从from开始找到标记的结束位置
+    let find = |from: usize, marker: &[char]| {
+        (from..chars.len()).find(|&j| chars[j..].starts_with(marker))
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let prev = if i > 0 { Some(chars[i - 1]) } else { None };
+
+        match c {
+            '\\' if next.is_some_and(|n| n.is_ascii_punctuation()) => {
+                result.push(chars[i + 1]);
+                i += 2;
+            }
+            '`' => match find(i + 1, &['`']) {
+                Some(end) => {
+                    let code: String = chars[i + 1..end].iter().collect();
+                    result.push_str(&paint(&code, "string"));
+                    // 上色之后会重置所有样式，恢复粗体和斜体
+                    if bold {
+                        result.push_str(style("\x1b[1m"));
+                    }
+                    if italic {
+                        result.push_str(style("\x1b[3m"));
+                    }
+                    i = end + 1;
+                }
+                None => {
+                    result.push(c);
+                    i += 1;
+                }
+            },
+            '*' | '_' if next == Some(c) && (bold || find(i + 2, &[c, c]).is_some()) => {
+                bold = !bold;
+                result.push_str(style(if bold { "\x1b[1m" } else { "\x1b[22m" }));
+                i += 2;
+            }
+            // _只在单词边界上表示斜体，避免snake_case被误认
+            '*' | '_' if (italic && !prev.is_some_and(char::is_whitespace))
+                || (!italic
+                    && next.is_some_and(|n| !n.is_whitespace())
+                    && (c == '*' || !prev.is_some_and(char::is_alphanumeric))
+                    && find(i + 1, &[c]).is_some()) =>
+            {
+                italic = !italic;
+                result.push_str(style(if italic { "\x1b[3m" } else { "\x1b[23m" }));
+                i += 1;
+            }
+            '[' => {
+                // [文字](链接)
+                let link = find(i + 1, &[']', '(']).and_then(|close| {
+                    find(close + 2, &[')']).map(|end| (close, end))
+                });
+                match link {
+                    Some((close, end)) => {
+                        let label: String = chars[i + 1..close].iter().collect();
+                        let url: String = chars[close + 2..end].iter().collect();
+                        result.push_str(&format!("{}{}{}", style("\x1b[4m"), render_inline(&label), style("\x1b[24m")));
+                        if label != url {
+                            result.push_str(&paint(&format!(" ({})", url), "hint"));
+                        }
+                        i = end + 1;
+                    }
+                    None => {
+                        result.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            _ => {
+                result.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    if bold || italic {
+        result.push_str(style("\x1b[0m"));
+    }
+    result
+}
+
+// 代码块中一行的高亮：shell代码使用和输入行相同的高亮，其他语言只区分关键字、字符串、数字和注释
+fn highlight_code(line: &str, lang: &str) -> String {
+    if !color_enabled() {
+        return line.to_string();
+    }
+
+    let lang = lang.to_lowercase();
+    let comments: &[&str] = match lang.as_str() {
+        "sh" | "bash" | "zsh" | "shell" | "console" | "psh" => {
+            if line.trim_start().starts_with('#') {
+                return paint(line, "hint");
+            }
+            let line = line.strip_prefix("$ ").unwrap_or(line);
+            return highlight_command(line, &|name| if BUILTINS.contains(&name) { "builtin" } else { "command" });
+        }
+        "python" | "py" | "ruby" | "rb" | "yaml" | "yml" | "toml" | "perl" | "r" | "make" | "makefile" | "dockerfile" | "ini" | "conf" => &["#"],
+        "sql" | "lua" | "haskell" | "hs" => &["--"],
+        "" | "text" | "txt" | "plain" => return line.to_string(),
+        _ => &["//"],
+    };
+
+    let chars: Vec<char> = line.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..].iter().collect();
+
+        if comments.iter().any(|prefix| rest.starts_with(prefix)) {
+            result.push_str(&paint(&rest, "hint"));
+            break;
+        }
+
+        if c == '"' || c == '\'' {
+            // 找到没有转义的闭合引号，没有的话一直到行尾
+            let mut end = i + 1;
+            while end < chars.len() && chars[end] != c {
+                end += if chars[end] == '\\' { 2 } else { 1 };
+            }
+            let end = (end + 1).min(chars.len());
+            let literal: String = chars[i..end].iter().collect();
+            result.push_str(&paint(&literal, "string"));
+            i = end;
+        } else if c.is_alphanumeric() || c == '_' {
+            let end = (i..chars.len()).find(|&j| !(chars[j].is_alphanumeric() || chars[j] == '_')).unwrap_or(chars.len());
+            let word: String = chars[i..end].iter().collect();
+            if c.is_ascii_digit() {
+                result.push_str(&paint(&word, "variable"));
+            } else if KEYWORDS.contains(&word.as_str()) {
+                result.push_str(&paint(&word, "builtin"));
+            } else {
+                result.push_str(&word);
+            }
+            i = end;
+        } else {
+            result.push(c);
+            i += 1;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 去掉颜色和样式，测试时输出是否支持颜色取决于运行环境
+    fn strip_styles(text: &str) -> String {
+        let mut result = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(char::is_ascii_alphabetic);
+            } else {
+                result.push(c);
+            }
+        }
+        result
+    }
+
+    fn render(chunks: &[&str]) -> Vec<String> {
+        let mut renderer = Renderer::new();
+        let mut out = Vec::new();
+        for chunk in chunks {
+            renderer.push(chunk, &mut out).unwrap();
+        }
+        renderer.finish(&mut out).unwrap();
+        strip_styles(&String::from_utf8(out).unwrap()).lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn renders_fenced_code_blocks() {
+        let lines = render(&["before\n``", "`sh\necho hi\n", "```\nafter\n"]);
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "before");
+        assert!(lines[1].starts_with("╭─ sh ─"), "{}", lines[1]);
+        assert_eq!(lines[2], "│ echo hi");
+        assert!(lines[3].starts_with("╰─"), "{}", lines[3]);
+        assert_eq!(lines[4], "after");
+    }
+
+    #[test]
+    fn closes_fences_only_with_the_same_marker() {
+        let lines = render(&["~~~\n```\n# not a heading\n~~~\n"]);
+        assert_eq!(lines[1], "│ ```");
+        assert_eq!(lines[2], "│ # not a heading");
+        assert!(lines[3].starts_with("╰─"));
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn closes_unterminated_fences_on_finish() {
+        let lines = render(&["```python\nx = 1"]);
+        assert!(lines[0].starts_with("╭─ python ─"));
+        assert_eq!(lines[1], "│ x = 1");
+        assert!(lines[2].starts_with("╰─"));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn renders_inline_markup() {
+        let inline = |text: &str| strip_styles(&render_inline(text));
+        assert_eq!(inline("run `ls -l` now"), "run ls -l now");
+        assert_eq!(inline("`a*b*c` and `x`"), "a*b*c and x");
+        assert_eq!(inline("a **bold** and __also__ b"), "a bold and also b");
+        assert_eq!(inline("**bold `code` still bold**"), "bold code still bold");
+        assert_eq!(inline("*it* and _it_"), "it and it");
+        assert_eq!(inline("snake_case_name"), "snake_case_name");
+        assert_eq!(inline("unclosed `tick"), "unclosed `tick");
+        assert_eq!(inline("\\*literal\\*"), "*literal*");
+        assert_eq!(inline("[docs](https://example.com)"), "docs (https://example.com)");
+    }
+
+    #[test]
+    fn renders_bold_with_styles_when_colored() {
+        if !color_enabled() {
+            return;
+        }
+        assert_eq!(render_inline("a **b** c"), "a \x1b[1mb\x1b[22m c");
+    }
+}
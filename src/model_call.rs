@@ -1,15 +1,28 @@
 use std::env;
-use dotenvy::dotenv;
-use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
-use reqwest::Client;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Client, Response, StatusCode};
 use serde_json::{json, Value};
+use tokio::runtime::Runtime;
 
-use crate::error::ShellError;
+use crate::config;
+use crate::error::{self, ShellError};
+use crate::provider::{self, LlmProvider, Reply, StreamEvent, Tool};
 
 pub struct Config {
-    api_url: String,
-    api_key: String,
-    model_name: String,
+    pub api_url: String,
+    pub api_key: String,
+    pub model_name: String,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub top_p: Option<f64>,
+    // 加在对话最前面的system prompt
+    pub system: Option<String>,
 }
 impl Config {
     pub fn new(url: String, key: String, name: String) -> Self{
@@ -17,51 +30,400 @@ impl Config {
             api_url: url,
             api_key: key,
             model_name: name,
+            temperature: None,
+            max_tokens: None,
+            top_p: None,
+            system: None,
+        }
+    }
+
+    // LLM_<PROVIDER>_API_URL等变量只对该provider生效，优先于通用的LLM_API_URL等
+    // 通用的变量只用于LLM_PROVIDER选择的默认provider，避免--provider切换后用错地址和key
+    // params中没有指定的模型参数使用LLM_TEMPERATURE等默认值
+    pub fn from_env(provider: &dyn LlmProvider, params: &Params) -> Result<Self, ShellError> {
+        let is_default = provider.name() == provider::default_name();
+        let specific = |field: &str| format!("LLM_{}_{}", provider.name().to_uppercase(), field);
+        let var = |field: &str| -> Option<String> {
+            env::var(specific(field)).ok()
+                .or_else(|| if is_default { env::var(format!("LLM_{}", field)).ok() } else { None })
+                .filter(|value| !value.is_empty())
+        };
+        // 缺少设置时说明应该设置哪个变量
+        let missing = |field: &str| {
+            let names = if is_default {
+                format!("LLM_{} (or {})", field, specific(field))
+            } else {
+                specific(field)
+            };
+            ShellError::LLMError(format!(
+                "{} is not set; set it in the environment or in {}",
+                names, config::config_path().display()
+            ))
+        };
+
+        let api_key = match var("API_KEY") {
+            Some(key) => key,
+            None if provider.needs_key() => return Err(missing("API_KEY")),
+            None => String::new(),
+        };
+        let model_name = match &params.model {
+            Some(model) => model.clone(),
+            None => var("MODEL_NAME").ok_or_else(|| missing("MODEL_NAME"))?,
+        };
+
+        let defaults = Params::from_vars("LLM_")?;
+        let mut config = Config::new(
+            var("API_URL").unwrap_or_else(|| provider.default_url().to_string()),
+            api_key,
+            model_name,
+        );
+        config.temperature = params.temperature.or(defaults.temperature);
+        config.max_tokens = params.max_tokens.or(defaults.max_tokens);
+        config.top_p = params.top_p.or(defaults.top_p);
+        config.system = params.system.clone().or(defaults.system);
+        Ok(config)
+    }
+}
+
+// 一次调用使用的provider、模型和参数，来自chat的选项或者配置文件中的profile
+#[derive(Default)]
+pub struct Params {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub temperature: Option<f64>,
+    pub max_tokens: Option<u32>,
+    pub top_p: Option<f64>,
+    pub system: Option<String>,
+}
+
+impl Params {
+    // 配置文件中名为name的profile，例如：
+    //   LLM_PROFILE_FAST_MODEL=gpt-4o-mini
+    //   LLM_PROFILE_FAST_TEMPERATURE=0.2
+    // 可以设置PROVIDER、MODEL、TEMPERATURE、MAX_TOKENS、TOP_P、SYSTEM和SYSTEM_FILE
+    pub fn profile(name: &str) -> Result<Self, ShellError> {
+        let prefix = format!("LLM_PROFILE_{}_", name.to_uppercase().replace('-', "_"));
+        if !env::vars().any(|(key, _)| key.starts_with(&prefix)) {
+            return Err(ShellError::LLMError(format!(
+                "unknown profile '{}'; define it with {}MODEL=... etc. in {}",
+                name, prefix, config::config_path().display()
+            )));
+        }
+
+        let mut params = Params::from_vars(&prefix)?;
+        params.provider = env_value(&format!("{}PROVIDER", prefix));
+        params.model = env_value(&format!("{}MODEL", prefix));
+        Ok(params)
+    }
+
+    // 读取<prefix>TEMPERATURE、MAX_TOKENS、TOP_P、SYSTEM和SYSTEM_FILE
+    fn from_vars(prefix: &str) -> Result<Self, ShellError> {
+        let parse = |field: &str| {
+            let name = format!("{}{}", prefix, field);
+            env_value(&name).map(|value| (name, value))
+        };
+
+        let mut params = Params::default();
+        if let Some((name, value)) = parse("TEMPERATURE") {
+            params.temperature = Some(parse_temperature(&name, &value)?);
+        }
+        if let Some((name, value)) = parse("MAX_TOKENS") {
+            params.max_tokens = Some(parse_max_tokens(&name, &value)?);
+        }
+        if let Some((name, value)) = parse("TOP_P") {
+            params.top_p = Some(parse_top_p(&name, &value)?);
+        }
+        params.system = env_value(&format!("{}SYSTEM", prefix));
+        if let Some((_, file)) = parse("SYSTEM_FILE") {
+            // 配置文件中的相对路径相对于配置目录
+            let path = PathBuf::from(&file);
+            let path = if path.is_relative() { config::config_dir().join(path) } else { path };
+            params.system = Some(read_system_file(&path)?);
+        }
+        Ok(params)
+    }
+
+    // 没有指定的值使用other中的值
+    pub fn or(self, other: Params) -> Params {
+        Params {
+            provider: self.provider.or(other.provider),
+            model: self.model.or(other.model),
+            temperature: self.temperature.or(other.temperature),
+            max_tokens: self.max_tokens.or(other.max_tokens),
+            top_p: self.top_p.or(other.top_p),
+            system: self.system.or(other.system),
         }
     }
 }
 
-pub async fn llm_call(message: String) -> Result<String, ShellError> {
-    dotenv().ok();
+fn env_value(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn invalid(name: &str, value: &str, expected: &str) -> ShellError {
+    ShellError::LLMError(format!("invalid {} '{}': expected {}", name, value, expected))
+}
+
+pub fn parse_temperature(name: &str, value: &str) -> Result<f64, ShellError> {
+    value.parse::<f64>().ok()
+        .filter(|temperature| (0.0..=2.0).contains(temperature))
+        .ok_or_else(|| invalid(name, value, "a number from 0 to 2"))
+}
+
+pub fn parse_top_p(name: &str, value: &str) -> Result<f64, ShellError> {
+    value.parse::<f64>().ok()
+        .filter(|top_p| (0.0..=1.0).contains(top_p))
+        .ok_or_else(|| invalid(name, value, "a number from 0 to 1"))
+}
+
+pub fn parse_max_tokens(name: &str, value: &str) -> Result<u32, ShellError> {
+    value.parse::<u32>().ok()
+        .filter(|max_tokens| *max_tokens > 0)
+        .ok_or_else(|| invalid(name, value, "a positive integer"))
+}
+
+pub fn read_system_file(path: &Path) -> Result<String, ShellError> {
+    fs::read_to_string(path)
+        .map_err(|e| ShellError::LLMError(format!("Failed to read system prompt '{}': {}", path.display(), e)))
+}
 
-    let client = Client::new();
+// 整个shell共用的tokio运行时和HTTP客户端，第一次调用模型时创建
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+static CLIENT: OnceLock<Client> = OnceLock::new();
 
-    let config = Config::new(
-        env::var("LLM_API_URL").expect("psh: LLM_API_URL not set"),
-        env::var("LLM_API_KEY").expect("psh: LLM_API_KEY not set"),
-        env::var("LLM_MODEL_NAME").expect("psh: LLM_MODEL_NAME not set"),
-    );
+// 429和5xx时最多重试的次数，可以用PSH_LLM_RETRIES设置
+const DEFAULT_RETRIES: u32 = 3;
+// 重试的等待时间从0.5秒开始每次翻倍，最长30秒
+const RETRY_BASE: Duration = Duration::from_millis(500);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+// 服务器要求等待更久（Retry-After）时不再重试
+const RETRY_AFTER_MAX: Duration = Duration::from_secs(60);
 
-    let payload = json!({
-        "model": config.model_name,
-        "messages": [
-            {
-                "role": "user",
-                "content": message
+fn runtime() -> Result<&'static Runtime, ShellError> {
+    if let Some(rt) = RUNTIME.get() {
+        return Ok(rt);
+    }
+    let rt = Runtime::new()?;
+    Ok(RUNTIME.get_or_init(|| rt))
+}
+
+// 连接超时（PSH_LLM_CONNECT_TIMEOUT，默认10秒）和两次收到数据之间的超时（PSH_LLM_READ_TIMEOUT，默认120秒）
+fn client() -> Result<&'static Client, ShellError> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let client = Client::builder()
+        .connect_timeout(env_seconds("PSH_LLM_CONNECT_TIMEOUT", 10.0))
+        .read_timeout(env_seconds("PSH_LLM_READ_TIMEOUT", 120.0))
+        .build()
+        .map_err(|e| ShellError::LLMError(format!("Failed to create HTTP client: {}", e)))?;
+    Ok(CLIENT.get_or_init(|| client))
+}
+
+fn env_seconds(name: &str, default: f64) -> Duration {
+    let seconds = env::var(name).ok()
+        .and_then(|value| value.parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+        .unwrap_or(default);
+    Duration::from_secs_f64(seconds)
+}
+
+// 发送完整的对话历史，返回模型的完整回复
+// 回复以流式（server-sent events或JSON Lines）接收，每收到一段就调用on_delta，使输出可以边生成边显示
+// 按下Ctrl+C时取消请求（包括重试前的等待）；设置PSH_CHAT_STREAM=0时一次性接收
+pub fn llm_call(provider: &dyn LlmProvider, params: &Params, messages: Vec<Value>, on_delta: &mut dyn FnMut(&str) -> Result<(), ShellError>) -> Result<String, ShellError> {
+    let stream = env::var("PSH_CHAT_STREAM").map_or(true, |v| !matches!(v.as_str(), "0" | "off" | "false" | "no"));
+    let reply = block_on(request(provider, params, &messages, &[], stream, on_delta))?;
+    Ok(reply.text)
+}
+
+// 提供工具的请求，一次性接收回复，其中可能包含工具调用
+pub fn llm_call_tools(provider: &dyn LlmProvider, params: &Params, messages: &[Value], tools: &[Tool]) -> Result<Reply, ShellError> {
+    block_on(request(provider, params, messages, tools, false, &mut |_| Ok(())))
+}
+
+fn block_on(request: impl Future<Output = Result<Reply, ShellError>>) -> Result<Reply, ShellError> {
+    runtime()?.block_on(async {
+        tokio::select! {
+            result = request => result,
+            _ = tokio::signal::ctrl_c() => Err(ShellError::LLMError("Request cancelled".to_string())),
+        }
+    })
+}
+
+async fn request(provider: &dyn LlmProvider, params: &Params, messages: &[Value], tools: &[Tool], stream: bool, on_delta: &mut dyn FnMut(&str) -> Result<(), ShellError>) -> Result<Reply, ShellError> {
+    let config = Config::from_env(provider, params)?;
+    let messages: Vec<Value> = config.system.iter()
+        .map(|system| json!({ "role": "system", "content": system }))
+        .chain(messages.iter().cloned())
+        .collect();
+    let response = send(provider, &config, &messages, tools, stream).await?;
+
+    // 服务器不支持流式时会直接返回JSON
+    let content_type = response.headers().get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if content_type.starts_with("text/event-stream") {
+        return read_stream(provider, response, true, on_delta).await.map(Reply::text);
+    }
+    if content_type.starts_with("application/x-ndjson") {
+        return read_stream(provider, response, false, on_delta).await.map(Reply::text);
+    }
+
+    let body = response.text().await.map_err(connection_lost)?;
+    let json_resp: Value = serde_json::from_str(&body).map_err(|_| ShellError::LLMError(format!(
+        "the API returned a response that is not JSON ({}); check that LLM_API_URL points to the {} endpoint",
+        excerpt(&body), provider.name()
+    )))?;
+    let reply = provider.parse_response(&json_resp)?;
+    on_delta(&reply.text)?;
+    Ok(reply)
+}
+
+// 发送请求，遇到429、5xx、连接失败或超时时按指数退避加随机抖动重试，服务器给出Retry-After时按它等待
+// 只在收到回复内容之前重试，已经输出的部分不会重复
+async fn send(provider: &dyn LlmProvider, config: &Config, messages: &[Value], tools: &[Tool], stream: bool) -> Result<Response, ShellError> {
+    let client = client()?;
+    let retries = env::var("PSH_LLM_RETRIES").ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_RETRIES);
+
+    let mut attempt = 0;
+    let result = loop {
+        let result = provider.request(client, config, messages, tools, stream).send().await;
+        let (reason, retry_after) = match &result {
+            Ok(response) if response.status().is_success() => break result,
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS || response.status().is_server_error() => {
+                (format!("HTTP {}", response.status()), retry_after(response))
             }
-        ],
-    });
-
-    let res = client.post(&config.api_url)
-        .header(CONTENT_TYPE, "application/json")
-        .header(AUTHORIZATION, format!("Bearer {}", config.api_key))
-        .json(&payload)
-        .send()
-        .await;
-
-    match res {
-        Ok(response) => {
-            if response.status().is_success() {
-                let json_resp: Value = response.json().await.unwrap();
-                if let Some(content) = json_resp["choices"][0]["message"]["content"].as_str() {
-                    Ok(format!("\nAI: {}\n", content))
-                } else {
-                    Err(ShellError::LLMError("Unknown API Error".to_string()))
-                }
+            Err(e) if e.is_timeout() => ("request timed out".to_string(), None),
+            Err(e) if e.is_connect() => ("connection failed".to_string(), None),
+            _ => break result,
+        };
+
+        attempt += 1;
+        let delay = retry_after.unwrap_or_else(|| backoff(attempt));
+        if attempt > retries || delay > RETRY_AFTER_MAX {
+            break result;
+        }
+        error::report(format!("{}, retrying in {:.1}s ({}/{})", reason, delay.as_secs_f64(), attempt, retries));
+        tokio::time::sleep(delay).await;
+    };
+
+    match result {
+        Ok(response) if response.status().is_success() => Ok(response),
+        Ok(response) => Err(status_error(response.status(), response).await),
+        Err(e) => Err(connection_error(&e)),
+    }
+}
+
+// 第n次重试前的等待时间：0.5s·2^(n-1)，再乘以0.5到1之间的随机数，避免多个客户端同时重试
+fn backoff(attempt: u32) -> Duration {
+    let delay = RETRY_BASE.saturating_mul(1 << (attempt - 1).min(16)).min(RETRY_MAX);
+    delay.mul_f64(rand::rng().random_range(0.5..=1.0))
+}
+
+// Retry-After可以是秒数，也可以是HTTP日期
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return (seconds.is_finite() && seconds >= 0.0).then(|| Duration::from_secs_f64(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or_default())
+}
+
+fn connection_error(e: &reqwest::Error) -> ShellError {
+    if e.is_timeout() {
+        ShellError::LLMError(format!("Request timed out (PSH_LLM_CONNECT_TIMEOUT/PSH_LLM_READ_TIMEOUT): {}", e))
+    } else {
+        ShellError::LLMError(format!("Connection Error: {}", e))
+    }
+}
+
+fn connection_lost(e: reqwest::Error) -> ShellError {
+    if e.is_timeout() {
+        ShellError::LLMError("Connection lost: no data received within PSH_LLM_READ_TIMEOUT".to_string())
+    } else {
+        ShellError::LLMError(format!("Connection lost: {}", e))
+    }
+}
+
+// 请求失败时的错误：HTTP状态加上API返回的error.message，没有时显示回复的开头
+async fn status_error(status: StatusCode, response: Response) -> ShellError {
+    let body = response.text().await.unwrap_or_default();
+    let message = serde_json::from_str::<Value>(&body).ok()
+        .and_then(|body| provider::error_message(&body))
+        .unwrap_or_else(|| excerpt(&body));
+
+    let hint = match status.as_u16() {
+        401 | 403 => " (check LLM_API_KEY)",
+        404 => " (check LLM_API_URL and LLM_MODEL_NAME)",
+        429 => " (rate limited, try again later)",
+        _ => "",
+    };
+    ShellError::LLMError(format!("API Error: HTTP {}: {}{}", status, message, hint))
+}
+
+// 回复内容的开头一行，用于错误信息
+fn excerpt(body: &str) -> String {
+    let line = body.trim().lines().next().unwrap_or_default();
+    if line.is_empty() {
+        return "empty response".to_string();
+    }
+    match line.char_indices().nth(200) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+// 逐行读取流式回复，每个事件交给provider解析
+// SSE格式中事件是 data: {json} 行，OpenAI以 data: [DONE] 结束；JSON Lines格式中每行就是一个事件
+async fn read_stream(provider: &dyn LlmProvider, mut response: Response, event_stream: bool, on_delta: &mut dyn FnMut(&str) -> Result<(), ShellError>) -> Result<String, ShellError> {
+    let mut content = String::new();
+    // 还没有凑成完整一行的字节，一个UTF-8字符可能被分在两个块中
+    let mut buffer: Vec<u8> = Vec::new();
+
+    loop {
+        let chunk = response.chunk().await.map_err(connection_lost)?;
+        let Some(chunk) = chunk else {
+            // 没有收到结束事件就结束了，把已经收到的内容作为回复
+            return Ok(content);
+        };
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(newline) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            let data = if event_stream {
+                // 空行、注释和event:等其他字段
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                data.trim()
             } else {
-                Err(ShellError::LLMError(format!("API Error: {:?}", response.text().await)))
+                line
+            };
+
+            if data.is_empty() {
+                continue;
+            }
+            if data == "[DONE]" {
+                return Ok(content);
+            }
+
+            let event: Value = serde_json::from_str(data)
+                .map_err(|e| ShellError::LLMError(format!("Invalid stream data: {}", e)))?;
+            match provider.parse_event(&event)? {
+                StreamEvent::Text(delta) => {
+                    on_delta(&delta)?;
+                    content.push_str(&delta);
+                }
+                StreamEvent::Done => return Ok(content),
+                StreamEvent::Skip => {}
             }
         }
-        Err(e) => Err(ShellError::LLMError(format!("Connection Error: {}", e))),
     }
 }
\ No newline at end of file
@@ -1,3 +1,4 @@
+use crate::args_analysis::{redirection_analysis, Redirection};
 use crate::error::ShellError;
 
 // 这个Enum定义了Command的状态
@@ -5,74 +6,186 @@ use crate::error::ShellError;
 pub enum Command {
     Empty,
     Exit,
-    Builtin(String, Vec<String>),
+    // 内建命令的重定向在解析时就从参数中分离出来
+    Builtin(String, Vec<String>, Redirection),
     External(String, Vec<String>),
     Background(Box<Command>),
     Pipe(Box<Command>, Box<Command>),
 }
 
+// 所有内建命令的名字，补全等功能也使用这份列表
+pub const BUILTINS: &[&str] = &["cd", "pwd", "echo", "ls", "grep", "chat", "history", "complete", "set", "bind", "theme", "ask", "why"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Word,
+    Pipe,
+    Background,
+}
+
+// 词法分析得到的token
+// start和end是token在原始输入中的字节范围，text是去掉引号和转义后的内容
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub start: usize,
+    pub end: usize,
+    // 如果token结束时引号还没闭合，记录这个引号字符
+    pub open_quote: Option<char>,
+    // 是否包含引号或转义，这样的 > 和 < 按普通参数处理
+    pub quoted: bool,
+}
+
+// 参数是自然语言的内建命令，其中的撇号（如 chat what's up）没有闭合时不作为引号处理
+const FREE_TEXT_BUILTINS: &[&str] = &["chat", "ask", "why"];
+
+/// 把一行输入切分成token
+/// 单引号和双引号内的内容原样保留，引号外的反斜杠转义下一个字符
+/// 引号外的 | 和行尾的 & 是单独的token，其他位置的 & 是普通字符
+pub fn tokenize(line: &str) -> Vec<Token> {
+    tokenize_with(line, true)
+}
+
+fn tokenize_with(line: &str, quotes: bool) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let is_background = |i: usize| line[i + 1..].trim().is_empty();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some(&(start, ch)) = chars.peek() {
+        if ch.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if ch == '|' || (ch == '&' && is_background(start)) {
+            chars.next();
+            tokens.push(Token {
+                kind: if ch == '|' { TokenKind::Pipe } else { TokenKind::Background },
+                text: ch.to_string(),
+                start,
+                end: start + 1,
+                open_quote: None,
+                quoted: false,
+            });
+            continue;
+        }
+
+        // 普通单词，一直读到引号外的空白或操作符
+        let mut text = String::new();
+        let mut quote: Option<char> = None;
+        let mut quoted = false;
+        let mut end = start;
+
+        while let Some(&(i, c)) = chars.peek() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                Some(_) => text.push(c),
+                None if c.is_whitespace() || c == '|' || (c == '&' && is_background(i)) => break,
+                None if quotes && (c == '\'' || c == '"') => {
+                    quote = Some(c);
+                    quoted = true;
+                }
+                None if c == '\\' => {
+                    chars.next();
+                    quoted = true;
+                    end = i + 1;
+                    if let Some(&(j, escaped)) = chars.peek() {
+                        text.push(escaped);
+                        end = j + escaped.len_utf8();
+                        chars.next();
+                    }
+                    continue;
+                }
+                None => text.push(c),
+            }
+            end = i + c.len_utf8();
+            chars.next();
+        }
+
+        tokens.push(Token {
+            kind: TokenKind::Word,
+            text,
+            start,
+            end,
+            open_quote: quote,
+            quoted,
+        });
+    }
+
+    tokens
+}
 
 // 在这种parse机制的处理逻辑中，& 符号会作用于多个管道连接起来的整体
 // 如果在管道连接的命令内部使用&，如 cmd & | cmd & 的形式，会出现解析错误
 // TODO: 处理管道命令内部使用&的情况
 pub fn parse_line(line: &str) -> Result<Command, ShellError> {
-    // 去除空格
-    let mut line = line.trim();
-    let is_background:bool;
+    let mut tokens = tokenize(line);
+    if tokens.iter().any(|t| t.open_quote.is_some()) && is_free_text(&tokens) {
+        tokens = tokenize_with(line, false);
+    }
 
     // 检查是否后台命令
-    is_background = if line.ends_with('&') {
-        // 移除&符号,再trim一遍
-        line = line[..line.len()-1].trim();
+    let is_background = if tokens.last().is_some_and(|t| t.kind == TokenKind::Background) {
+        // 移除&符号
+        tokens.pop();
         true
     } else {
         false
     };
 
     // 处理空命令
-    if line.is_empty() {
+    if tokens.is_empty() {
         return Ok(Command::Empty);
     }
 
-    let command = parse_command(line, is_background)?;
+    if let Some(token) = tokens.iter().find(|t| t.open_quote.is_some()) {
+        return Err(ShellError::ParseError(format!("Unterminated quote {}", token.open_quote.unwrap())));
+    }
+
+    let command = parse_command(&tokens)?;
 
     test_background(command, is_background)
 }
 
+// 没有闭合的引号一直延续到行尾，所以只需要看最后一个管道之后的命令
+fn is_free_text(tokens: &[Token]) -> bool {
+    let start = tokens.iter().rposition(|t| t.kind == TokenKind::Pipe).map_or(0, |i| i + 1);
+    tokens.get(start).is_some_and(|t| FREE_TEXT_BUILTINS.contains(&t.text.as_str()))
+}
+
 // 解析命令。单独拿出这个函数是方便递归地嵌套Pipe
-fn parse_command(cmd : &str, is_background: bool) -> Result<Command, ShellError>{
-    // 如果存在管道符号，那就从从第一个管道处拆分出左右两个子串
-    if let Some((first_cmd, other)) = cmd.split_once('|'){
-        // 递归地解析两个字串
-        let former_command = parse_command(first_cmd, is_background)?;
-        let latter_command = parse_command(other, is_background)?;
+fn parse_command(tokens: &[Token]) -> Result<Command, ShellError>{
+    // 如果存在管道符号，那就从从第一个管道处拆分出左右两个部分
+    if let Some(index) = tokens.iter().position(|t| t.kind == TokenKind::Pipe) {
+        // 递归地解析两个部分
+        let former_command = parse_command(&tokens[..index])?;
+        let latter_command = parse_command(&tokens[index + 1..])?;
         // 包裹在Command::Pipe中返回
         Ok(Command::Pipe(Box::new(former_command), Box::new(latter_command)))
     } else {// 如果是不存在管道符号的普通命令
 
-        let cmd = cmd.trim();
-
-        if cmd.is_empty() {
+        if tokens.is_empty() {
             return Ok(Command::Empty);
         }
 
-        let mut parsed : Vec<String> = cmd
-            .split_whitespace()
-            .map(String::from)
-            .collect();
-
         // 分割出命令名和参数
-        let cmd_name = parsed.remove(0);
-        let args = parsed;
+        let cmd_name = tokens[0].text.clone();
+        let mut args = tokens[1..].to_vec();
+        let texts = |args: Vec<Token>| args.into_iter().map(|t| t.text).collect();
 
         let command = match cmd_name.as_str() {
             "exit" => Command::Exit,
             "quit" => Command::Empty,
-            "cd" | "pwd" | "echo" | "ls" | "grep" | "chat" => Command::Builtin(cmd_name, args),
-            _ => Command::External(cmd_name, args),
+            name if BUILTINS.contains(&name) => {
+                // 分析并移除重定向符号
+                let redirection = redirection_analysis(&mut args)?;
+                Command::Builtin(cmd_name, texts(args), redirection)
+            }
+            _ => Command::External(cmd_name, texts(args)),
         };
 
-        test_background(command, is_background)
+        Ok(command)
     }
 }
 
@@ -86,4 +199,71 @@ fn test_background(command: Command, is_background: bool) -> Result<Command, She
     } else {
         Ok(command)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builtin(line: &str) -> (Vec<String>, Redirection) {
+        match parse_line(line).unwrap() {
+            Command::Builtin(_, args, redirection) => (args, redirection),
+            command => panic!("not a builtin: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn tokenizes_quotes_and_escapes() {
+        let tokens = tokenize(r#"echo "a b" 'c|d' e\ f|wc"#);
+        let texts: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+        assert_eq!(texts, ["echo", "a b", "c|d", "e f", "|", "wc"]);
+        let quoted: Vec<bool> = tokens.iter().map(|t| t.quoted).collect();
+        assert_eq!(quoted, [false, true, true, true, false, false]);
+        assert_eq!(tokenize("echo 'abc")[1].open_quote, Some('\''));
+    }
+
+    #[test]
+    fn separates_unquoted_redirections() {
+        let (args, redirection) = builtin("echo hi > out < in");
+        assert_eq!(args, ["hi"]);
+        assert_eq!(redirection.output_file.as_deref(), Some("out"));
+        assert_eq!(redirection.input_file.as_deref(), Some("in"));
+        assert!(parse_line("echo hi >").is_err());
+    }
+
+    #[test]
+    fn keeps_quoted_redirection_symbols() {
+        for line in [r#"echo ">" x"#, "echo '>' x", r"echo \> x"] {
+            let (args, redirection) = builtin(line);
+            assert_eq!(args, [">", "x"], "{}", line);
+            assert!(redirection.output_file.is_none(), "{}", line);
+        }
+        let (args, redirection) = builtin(r#"echo "<" x"#);
+        assert_eq!(args, ["<", "x"]);
+        assert!(redirection.input_file.is_none());
+    }
+
+    #[test]
+    fn keeps_apostrophes_in_free_text() {
+        let (args, _) = builtin("chat what's up");
+        assert_eq!(args, ["what's", "up"]);
+        let (args, redirection) = builtin("chat don't \"quote\" me > out");
+        assert_eq!(args, ["don't", "\"quote\"", "me"]);
+        assert_eq!(redirection.output_file.as_deref(), Some("out"));
+        assert!(matches!(parse_line("cat log | why it's failing &").unwrap(), Command::Background(_)));
+        // 其他命令仍然要求引号闭合
+        assert!(parse_line("echo what's up").is_err());
+    }
+
+    #[test]
+    fn treats_ampersand_as_background_only_at_end() {
+        assert!(matches!(parse_line("sleep 1 &").unwrap(), Command::Background(_)));
+        assert!(matches!(parse_line("sleep 1&  ").unwrap(), Command::Background(_)));
+        let (args, _) = builtin("chat tom & jerry");
+        assert_eq!(args, ["tom", "&", "jerry"]);
+        match parse_line("curl x?a=1&b=2").unwrap() {
+            Command::External(_, args) => assert_eq!(args, ["x?a=1&b=2"]),
+            command => panic!("not external: {:?}", command),
+        }
+    }
 }
\ No newline at end of file
@@ -1,12 +1,182 @@
+use std::cell::OnceCell;
 use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::sync::OnceLock;
+use std::time::Duration;
+use chrono::format::{Item, StrftimeItems};
 use colorgrad::Gradient;
+use unicode_width::UnicodeWidthStr;
+
+use crate::git::{self, GitStatus};
+use crate::theme;
 use rand::prelude::IndexedRandom;
 
+// --color参数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    Never,
+    Auto,
+    Always,
+}
+
+impl ColorChoice {
+    pub fn parse(value: &str) -> Option<ColorChoice> {
+        match value {
+            "never" => Some(ColorChoice::Never),
+            "auto" => Some(ColorChoice::Auto),
+            "always" => Some(ColorChoice::Always),
+            _ => None,
+        }
+    }
+}
+
+// 终端支持的颜色数量
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum ColorLevel {
+    None,
+    Ansi16,
+    Ansi256,
+    TrueColor,
+}
+
+static COLOR_CHOICE: OnceLock<ColorChoice> = OnceLock::new();
+static COLOR_LEVEL: OnceLock<ColorLevel> = OnceLock::new();
+
+// 在启动时根据--color参数设置，只能设置一次
+pub fn set_color_choice(choice: ColorChoice) {
+    let _ = COLOR_CHOICE.set(choice);
+}
+
+// 根据--color、NO_COLOR、COLORTERM、TERM以及输出是否为终端判断支持的颜色
+// auto时遵守NO_COLOR，TERM=dumb或者输出不是终端时不使用颜色；always时至少使用16色
+pub fn detect_color_level(is_terminal: bool) -> ColorLevel {
+    let choice = COLOR_CHOICE.get().copied().unwrap_or(ColorChoice::Auto);
+    let term = env::var("TERM").unwrap_or_default();
+
+    match choice {
+        ColorChoice::Never => return ColorLevel::None,
+        ColorChoice::Auto => {
+            let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+            if no_color || term == "dumb" || !is_terminal {
+                return ColorLevel::None;
+            }
+        }
+        ColorChoice::Always => {}
+    }
+
+    let colorterm = env::var("COLORTERM").unwrap_or_default();
+    if colorterm == "truecolor" || colorterm == "24bit" || term.ends_with("-direct") {
+        ColorLevel::TrueColor
+    } else if term.contains("256color") {
+        ColorLevel::Ansi256
+    } else {
+        ColorLevel::Ansi16
+    }
+}
+
+// 标准输出支持的颜色，第一次调用时检测
+pub fn color_level() -> ColorLevel {
+    *COLOR_LEVEL.get_or_init(|| detect_color_level(io::stdout().is_terminal()))
+}
+
+pub fn color_enabled() -> bool {
+    color_level() != ColorLevel::None
+}
+
+// 生成前景色的ANSI转义序列，终端不支持真彩色时转换为最接近的256色或16色
+pub fn color_code(r: u8, g: u8, b: u8) -> String {
+    color_code_for(color_level(), r, g, b)
+}
+
+pub fn color_code_for(level: ColorLevel, r: u8, g: u8, b: u8) -> String {
+    match level {
+        ColorLevel::TrueColor => format!("\x1b[38;2;{};{};{}m", r, g, b),
+        ColorLevel::Ansi256 => format!("\x1b[38;5;{}m", to_ansi256(r, g, b)),
+        ColorLevel::Ansi16 => {
+            let index = to_ansi16(r, g, b);
+            format!("\x1b[{}m", if index < 8 { 30 + index } else { 90 + index - 8 })
+        }
+        ColorLevel::None => String::new(),
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> i32 {
+    let (dr, dg, db) = (r1 as i32 - r2 as i32, g1 as i32 - g2 as i32, b1 as i32 - b2 as i32);
+    dr * dr + dg * dg + db * db
+}
+
+// 在6x6x6的颜色立方体和24级灰度中选择最接近的颜色
+fn to_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let nearest_level = |v: u8| (0..6).min_by_key(|&i| (LEVELS[i] as i32 - v as i32).abs()).unwrap();
+
+    let (ri, gi, bi) = (nearest_level(r), nearest_level(g), nearest_level(b));
+    let cube = (LEVELS[ri], LEVELS[gi], LEVELS[bi]);
+
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray_index = (average.saturating_sub(8) / 10).min(23) as u8;
+    let gray_value = 8 + gray_index * 10;
+    let gray = (gray_value, gray_value, gray_value);
+
+    if distance(gray, (r, g, b)) < distance(cube, (r, g, b)) {
+        232 + gray_index
+    } else {
+        16 + 36 * ri as u8 + 6 * gi as u8 + bi as u8
+    }
+}
+
+// 选择最接近的16色（xterm的默认配色）
+fn to_ansi16(r: u8, g: u8, b: u8) -> u8 {
+    const PALETTE: [(u8, u8, u8); 16] = [
+        (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0), (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+        (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0), (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+    ];
+    (0..16).min_by_key(|&i| distance(PALETTE[i as usize], (r, g, b))).unwrap()
+}
+
+// 文本在终端上显示的宽度
+// 忽略\x01和\x02之间的内容以及ANSI转义序列，emoji等宽字符按两列计算
+pub fn visible_width(text: &str) -> usize {
+    let mut visible = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\x01' => {
+                for c in chars.by_ref() {
+                    if c == '\x02' {
+                        break;
+                    }
+                }
+            }
+            '\x1b' => {
+                // CSI序列以0x40到0x7e之间的字符结尾，其他转义序列只占一个字符
+                if chars.next_if_eq(&'[').is_some() {
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+                } else {
+                    chars.next();
+                }
+            }
+            _ => visible.push(ch),
+        }
+    }
+    visible.width()
+}
+
 // 为文本应用渐变色
 // 通过\x01和\x02标记包裹ANSI转义序列，告诉rustyline这些是不可打印字符
 fn apply_gradient(text: &str, gradient: &dyn Gradient) -> String {
-    if text.is_empty() {
-        return String::new();
+    gradient_text(text, gradient, true)
+}
+
+// 为文本应用渐变色，wrap为false时不加\x01和\x02标记，用于直接输出到终端
+// 不使用颜色时原样返回文本
+pub fn gradient_text(text: &str, gradient: &dyn Gradient, wrap: bool) -> String {
+    if text.is_empty() || !color_enabled() {
+        return text.to_string();
     }
 
     let chars: Vec<char> = text.chars().collect();
@@ -25,73 +195,378 @@ fn apply_gradient(text: &str, gradient: &dyn Gradient) -> String {
         let color = gradient.at(t);
         let rgba = color.to_rgba8();
 
-        // 生成24位真彩色ANSI转义序列
-        let color_code = format!("\x1b[38;2;{};{};{}m", rgba[0], rgba[1], rgba[2]);
+        // 生成ANSI转义序列
+        let color_code = color_code(rgba[0], rgba[1], rgba[2]);
 
         // 用rustyline的不可打印字符标记包裹ANSI代码
         // \x01 标记不可打印序列的开始
         // \x02 标记不可打印序列的结束
-        result.push_str(&format!("\x01{}\x02{}", color_code, ch));
+        if wrap {
+            result.push_str(&format!("\x01{}\x02{}", color_code, ch));
+        } else {
+            result.push_str(&format!("{}{}", color_code, ch));
+        }
     }
 
     // 在末尾添加重置代码
-    result.push_str("\x01\x1b[0m\x02");
+    result.push_str(if wrap { "\x01\x1b[0m\x02" } else { "\x1b[0m" });
 
     result
 }
 
-pub fn get_prompt() -> String {
-    // 提示符
-    let mut prompt= String::new();
-    let username = whoami::username();
-    prompt = if username == "root".to_string() {
-        "\u{1F680} #".to_string()
-    } else {
-        get_emoji() + " $"
-    };
+// 上一条命令等提示符需要的信息，由main_loop提供
+pub struct PromptContext {
+    pub last_status: i32,
+    // 上一条命令的执行时间
+    pub duration: Duration,
+    pub jobs: usize,
+    // git状态比较耗时，只在模板用到时计算一次
+    git: OnceCell<Option<GitStatus>>,
+}
+
+impl PromptContext {
+    pub fn new(last_status: i32, duration: Duration, jobs: usize) -> Self {
+        PromptContext { last_status, duration, jobs, git: OnceCell::new() }
+    }
+
+    fn git(&self) -> Option<&GitStatus> {
+        self.git.get_or_init(git::status).as_ref()
+    }
+}
+
+// 默认的提示符模板，对应原来固定的样式，在git仓库中额外显示git状态，
+// 上一条命令耗时较长时显示执行时间，失败时显示退出状态:
+// username@hostname dir (branch) [time] took 12.3s
+// ✘ 127 emoji $
+const DEFAULT_PROMPT: &str = "\\{\\u@\\h\\} \\w \\(g(\\g) \\)[\\t]\\(T \\{took \\T\\}\\)\\n\\(?\\{✘ \\?\\} \\)\\e \\$ ";
+const DEFAULT_TIME_FORMAT: &str = "%d/%m/%Y %H:%M";
+// 执行时间超过这个秒数时\(T...\)才显示，可以通过PSH_DURATION_THRESHOLD修改
+const DEFAULT_DURATION_THRESHOLD: f64 = 5.0;
+
+// 提示符模板解析后的片段
+//   \u 用户名  \h 主机名  \w 当前路径（~代替HOME）  \W 当前目录名  \s 缩写的当前路径
+//   \t 时间（格式由PSH_TIME_FORMAT指定）  \D{fmt} 指定strftime格式的时间
+//   \? 上一条命令的退出状态  \T 上一条命令的执行时间  \j 后台任务数
+//   \e emoji（上一条命令失败时为难过的表情）  \$ 普通用户为$，root为#
+//   \g git分支和状态，如 main ↑1 ↓2 +!?（+有暂存的修改 !有未暂存的修改 ?有未跟踪的文件 …超时未检查完）
+//   \n 换行  \\ 反斜杠
+//   \{...\} 把其中的内容作为一个整体应用渐变色
+//   \(c...\) 条件片段，只有条件c成立时才显示：? 上一条命令失败  j 有后台任务  r 当前是root  g 在git仓库中
+//                                  T 上一条命令的执行时间超过阈值
+enum Segment {
+    Text(String),
+    Escape(char, String),
+    Group(Vec<Segment>),
+    Conditional(char, Vec<Segment>),
+}
+
+// 解析模板直到遇到closing对应的结束标记（\) 或 \}）或模板结尾
+fn parse_template(chars: &mut std::iter::Peekable<std::str::Chars>, closing: Option<char>) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            text.push(ch);
+            continue;
+        }
+
+        let Some(escape) = chars.next() else {
+            text.push(ch);
+            break;
+        };
+
+        if Some(escape) == closing {
+            break;
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(std::mem::take(&mut text)));
+        }
+
+        match escape {
+            '{' => segments.push(Segment::Group(parse_template(chars, Some('}')))),
+            '(' => {
+                let condition = chars.next().unwrap_or(' ');
+                segments.push(Segment::Conditional(condition, parse_template(chars, Some(')'))));
+            }
+            'D' if chars.peek() == Some(&'{') => {
+                chars.next();
+                let format: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                segments.push(Segment::Escape('D', format));
+            }
+            _ => segments.push(Segment::Escape(escape, String::new())),
+        }
+    }
+
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    segments
+}
+
+fn is_root() -> bool {
+    whoami::username() == "root"
+}
+
+// 转义序列应用渐变色时使用的主题部分，None表示不上色
+fn escape_segment(escape: char) -> Option<&'static str> {
+    match escape {
+        'u' => Some("user"),
+        'h' => Some("host"),
+        'w' | 'W' | 's' => Some("dir"),
+        't' | 'D' => Some("time"),
+        '?' => Some("status"),
+        'T' => Some("duration"),
+        'j' => Some("jobs"),
+        'g' => Some("git"),
+        _ => None,
+    }
+}
+
+// 计算转义序列的值
+fn escape_value(escape: char, arg: &str, ctx: &PromptContext) -> String {
+    match escape {
+        'u' => whoami::username(),
+        'h' => whoami::fallible::hostname().unwrap_or("unknown_hostname".to_string()),
+        'w' => display_dir(),
+        'W' => {
+            let dir = display_dir();
+            if dir == "/" || dir == "~" { dir } else { dir.rsplit('/').next().unwrap_or("").to_string() }
+        }
+        's' => short_dir(),
+        't' => format_time(&env::var("PSH_TIME_FORMAT").unwrap_or_else(|_| DEFAULT_TIME_FORMAT.to_string())),
+        'D' => format_time(arg),
+        '?' => ctx.last_status.to_string(),
+        'T' => format_duration(ctx.duration),
+        'j' => ctx.jobs.to_string(),
+        'g' => ctx.git().map(GitStatus::summary).unwrap_or_default(),
+        'e' => if ctx.last_status != 0 {
+            get_sad_emoji()
+        } else if is_root() {
+            "\u{1F680}".to_string()
+        } else {
+            get_emoji()
+        },
+        '$' => (if is_root() { "#" } else { "$" }).to_string(),
+        'n' => "\n".to_string(),
+        '\\' => "\\".to_string(),
+        // 未知的转义序列原样输出
+        _ => format!("\\{}", escape),
+    }
+}
+
+fn condition_holds(condition: char, ctx: &PromptContext) -> bool {
+    match condition {
+        '?' => ctx.last_status != 0,
+        'T' => {
+            let threshold = env::var("PSH_DURATION_THRESHOLD").ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_DURATION_THRESHOLD);
+            ctx.duration.as_secs_f64() >= threshold
+        }
+        'j' => ctx.jobs > 0,
+        'r' => is_root(),
+        'g' => ctx.git().is_some(),
+        _ => false,
+    }
+}
+
+// 不带颜色地渲染片段，用于\{...\}整体上色
+fn render_plain(segments: &[Segment], ctx: &PromptContext) -> String {
+    segments.iter().map(|segment| match segment {
+        Segment::Text(text) => text.clone(),
+        Segment::Escape(escape, arg) => escape_value(*escape, arg, ctx),
+        Segment::Group(children) => render_plain(children, ctx),
+        Segment::Conditional(condition, children) if condition_holds(*condition, ctx) => render_plain(children, ctx),
+        Segment::Conditional(..) => String::new(),
+    }).collect()
+}
 
-    // 用户名&主机
-    let hostname = whoami::fallible::hostname().unwrap_or("unknown_hostname".to_string());
-    let username_at_hostname = username + "@" + &hostname;
+// \{...\}整体上色时使用其中第一个上色的转义序列对应的主题部分
+fn group_segment(segments: &[Segment]) -> Option<&'static str> {
+    segments.iter().find_map(|segment| match segment {
+        Segment::Escape(escape, _) => escape_segment(*escape),
+        Segment::Group(children) | Segment::Conditional(_, children) => group_segment(children),
+        Segment::Text(_) => None,
+    })
+}
+
+fn render(segments: &[Segment], ctx: &PromptContext, theme: &theme::Theme) -> String {
+    segments.iter().map(|segment| match segment {
+        Segment::Text(text) => text.clone(),
+        Segment::Escape(escape, arg) => match escape_segment(*escape) {
+            Some(part) => apply_gradient(&escape_value(*escape, arg, ctx), &theme.gradient(part)),
+            None => escape_value(*escape, arg, ctx),
+        },
+        Segment::Group(children) => {
+            let part = group_segment(children).unwrap_or("default");
+            apply_gradient(&render_plain(children, ctx), &theme.gradient(part))
+        }
+        Segment::Conditional(condition, children) if condition_holds(*condition, ctx) => render(children, ctx, theme),
+        Segment::Conditional(..) => String::new(),
+    }).collect()
+}
+
+// 按strftime格式显示当前时间，格式无效时（如%Q）chrono会在输出时panic，所以改用默认格式
+fn format_time(format: &str) -> String {
+    let valid = !StrftimeItems::new(format).any(|item| matches!(item, Item::Error));
+    chrono::Local::now().format(if valid { format } else { DEFAULT_TIME_FORMAT }).to_string()
+}
+
+// 如 850ms 12.3s 2m05s 1h03m
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else if secs >= 1 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
 
-    // 当前路径
+// 当前路径，HOME替换为~
+fn display_dir() -> String {
     let current_dir_path = env::current_dir().unwrap_or_default();
     let current_dir_str = current_dir_path.to_str().unwrap_or("unknown_current_directory").to_string();
-    let display_dir = match env::var("HOME") {
-        Ok(home_dir) => current_dir_str.replace(&home_dir, "~"),
-        Err(_) => current_dir_str.to_string(),
-    };
-
-    // 当前时间
-    let now = chrono::Local::now();
-    let time = now.format("%d/%m/%Y %H:%M").to_string();
-
-    // 创建渐变颜色
-    let gradient = colorgrad::GradientBuilder::new()
-        .html_colors(&["#FF6B6B", "#FFA07A", "#FFD93D", "#6BCF7F", "#4ECDC4", "#45B7D1", "#9B59B6", "#E056FD"])
-        .build::<colorgrad::LinearGradient>()
-        .unwrap();
-
-    // 应用渐变到不同部分
-    let styled_username = apply_gradient(&username_at_hostname, &gradient);
-    let styled_dir = apply_gradient(&display_dir, &gradient);
-    let styled_time = apply_gradient(&time, &gradient);
-
-    // 最终样式:
-    // username @ hostname [time]
-    // emoji
-    format!(
-        "{} {} [{}]\n{} ",
-        styled_username, styled_dir, styled_time, prompt
-    )
+    match env::var("HOME") {
+        Ok(home_dir) if current_dir_str.starts_with(&home_dir) => current_dir_str.replacen(&home_dir, "~", 1),
+        _ => current_dir_str,
+    }
 }
 
-pub fn get_emoji() -> String{
-    let emoji_choices = ["😀", "😃", "😅", "🥲", "🤯", "😝", "😚", "🤥", "💩", "🤡",
+// fish风格的缩写路径：除最后一级外每级只保留首字母，如 ~/p/c/src
+fn short_dir() -> String {
+    let dir = display_dir();
+    let parts: Vec<&str> = dir.split('/').collect();
+    let last = parts.len() - 1;
+    parts.iter().enumerate().map(|(i, part)| {
+        if i == last || part.is_empty() {
+            part.to_string()
+        } else {
+            let mut chars = part.chars();
+            // 隐藏目录保留.和首字母
+            match chars.next() {
+                Some('.') => format!(".{}", chars.next().map(String::from).unwrap_or_default()),
+                Some(c) => c.to_string(),
+                None => String::new(),
+            }
+        }
+    }).collect::<Vec<_>>().join("/")
+}
+
+fn render_template(template: &str, ctx: &PromptContext) -> String {
+    let segments = parse_template(&mut template.chars().peekable(), None);
+    theme::with_current(|theme| render(&segments, ctx, theme))
+}
+
+// 根据PSH_PROMPT模板生成提示符，没有设置时使用默认模板
+pub fn get_prompt(ctx: &PromptContext) -> String {
+    let template = env::var("PSH_PROMPT").unwrap_or_else(|_| DEFAULT_PROMPT.to_string());
+    render_template(&template, ctx)
+}
+
+// 根据PSH_RPROMPT模板生成显示在输入行右侧的提示符，如 PSH_RPROMPT='\(g\g \)\t'
+pub fn get_right_prompt(ctx: &PromptContext) -> Option<String> {
+    env::var("PSH_RPROMPT").ok().map(|template| render_template(&template, ctx))
+}
+
+// 设置了PSH_TRANSIENT_PROMPT时，命令执行前把提示符替换为这个模板，如 PSH_TRANSIENT_PROMPT='\e \$ '
+pub fn get_transient_prompt(ctx: &PromptContext) -> Option<String> {
+    env::var("PSH_TRANSIENT_PROMPT").ok().map(|template| render_template(&template, ctx))
+}
+
+// 输入被接受后，把屏幕上的提示符和输入行替换为简短的transient提示符，使滚动历史更紧凑
+// line用于计算占用的行数，display是实际输出的（上色后的）输入行
+pub fn collapse_prompt(prompt: &str, transient: &str, line: &str, display: &str, columns: usize) {
+    if !io::stdout().is_terminal() || columns == 0 {
+        return;
+    }
+
+    // 提示符和输入行一共占用的行数，rustyline在输入行末尾已经换了行
+    let mut lines: Vec<&str> = prompt.split('\n').collect();
+    let last = lines.pop().unwrap_or("");
+    let rows = lines.iter().map(|l| visible_width(l).max(1).div_ceil(columns)).sum::<usize>()
+        + (visible_width(last) + visible_width(line)) / columns + 1;
+
+    let mut stdout = io::stdout();
+    let _ = write!(stdout, "\x1b[{}A\r\x1b[J{}{}\n", rows, transient.replace(['\x01', '\x02'], ""), display);
+    let _ = stdout.flush();
+}
+
+const EMOJI_CHOICES: [&str; 46] = ["😀", "😃", "😅", "🥲", "🤯", "😝", "😚", "🤥", "💩", "🤡",
                                   "🥱", "😔", "🥳", "🤪", "🥰", "😇", "🫢", "🫠", "🤕", "🤠",
                                   "🤑", "👽", "😈", "🤖", "😮", "😋", "😉", "🙃", "😇", "😃",
                                   "👻", "😶", "😑", "😶‍🌫️", "🙂‍↕️", "🥶", "☺️", "🥹", "😁", "😮‍💨",
                                   "🦀", "🦀", "🦀", "🦀", "🦀", "🦀"];
+
+// EMOJI_CHOICES中难过的表情，上一条命令失败时使用
+const SAD_EMOJI_CHOICES: [&str; 8] = ["🥲", "🤯", "😔", "🫠", "🤕", "😑", "🥶", "😮‍💨"];
+
+pub fn get_emoji() -> String{
     let mut rng = rand::rng();
-    emoji_choices.choose(&mut rng).unwrap().to_string()
+    EMOJI_CHOICES.choose(&mut rng).unwrap().to_string()
+}
+
+pub fn get_sad_emoji() -> String {
+    let mut rng = rand::rng();
+    SAD_EMOJI_CHOICES.choose(&mut rng).unwrap().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(template: &str, ctx: &PromptContext) -> String {
+        render_plain(&parse_template(&mut template.chars().peekable(), None), ctx)
+    }
+
+    fn ctx(last_status: i32, jobs: usize) -> PromptContext {
+        PromptContext::new(last_status, Duration::ZERO, jobs)
+    }
+
+    #[test]
+    fn parses_template() {
+        assert_eq!(plain("a\\nb \\\\ c", &ctx(0, 0)), "a\nb \\ c");
+        assert_eq!(plain("\\{x\\}y", &ctx(0, 0)), "xy");
+        assert_eq!(plain("[\\?] \\j", &ctx(2, 3)), "[2] 3");
+        // 未知的转义序列和末尾的反斜杠原样保留
+        assert_eq!(plain("\\q a\\", &ctx(0, 0)), "\\q a\\");
+        // 没有结束标记时到模板结尾为止
+        assert_eq!(plain("\\{open", &ctx(0, 0)), "open");
+    }
+
+    #[test]
+    fn parses_conditionals() {
+        let template = "\\(?\\{✘ \\?\\} \\)ok\\(j (\\j)\\)";
+        assert_eq!(plain(template, &ctx(1, 0)), "✘ 1 ok");
+        assert_eq!(plain(template, &ctx(0, 2)), "ok (2)");
+        assert_eq!(plain("\\(x never\\)shown", &ctx(1, 1)), "shown");
+    }
+
+    #[test]
+    fn formats_time() {
+        // 没有格式说明符时原样输出
+        assert_eq!(plain("\\D{at noon} \\D{}", &ctx(0, 0)), "at noon ");
+        assert_eq!(format_time("%%").as_str(), "%");
+        // 无效的格式使用默认格式，如 19/10/2026 14:05
+        for format in ["%Q", "%", "%Y-%"] {
+            let time = plain(&format!("\\D{{{}}}", format), &ctx(0, 0));
+            assert_eq!(time.len(), 16, "{}", format);
+            assert_eq!(format_time(format).len(), 16, "{}", format);
+        }
+        assert_eq!(format_time("%H:%M").len(), 5);
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(Duration::from_millis(850)), "850ms");
+        assert_eq!(format_duration(Duration::from_millis(12_340)), "12.3s");
+        assert_eq!(format_duration(Duration::from_secs(59)), "59.0s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m05s");
+        assert_eq!(format_duration(Duration::from_secs(3600 + 180 + 9)), "1h03m");
+    }
 }
\ No newline at end of file
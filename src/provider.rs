@@ -0,0 +1,703 @@
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, RequestBuilder};
+use serde_json::{json, Value};
+
+use crate::error::ShellError;
+use crate::model_call::Config;
+
+// 提供给模型调用的工具，parameters是JSON Schema
+pub struct Tool {
+    pub name: &'static str,
+    pub description: String,
+    pub parameters: Value,
+}
+
+// 模型请求的一次工具调用
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: Value,
+}
+
+// 一次完整的回复
+pub struct Reply {
+    pub text: String,
+    pub tool_calls: Vec<ToolCall>,
+    // 原样加入对话的assistant消息，其中包含工具调用
+    pub message: Value,
+}
+
+impl Reply {
+    pub fn text(text: String) -> Self {
+        let message = json!({ "role": "assistant", "content": text });
+        Reply { text, tool_calls: Vec::new(), message }
+    }
+}
+
+// 流式回复中一个事件的含义
+pub enum StreamEvent {
+    Text(String),
+    Done,
+    Skip,
+}
+
+/// 一种LLM接口：负责构造请求（地址、认证头、请求体）和解析回复
+pub trait LlmProvider {
+    // 在--provider、LLM_PROVIDER和LLM_<NAME>_*环境变量中使用的名称
+    fn name(&self) -> &'static str;
+    // 没有设置LLM_API_URL时使用的地址
+    fn default_url(&self) -> &'static str;
+    // 是否必须设置API key
+    fn needs_key(&self) -> bool {
+        true
+    }
+    fn request(&self, client: &Client, config: &Config, messages: &[Value], tools: &[Tool], stream: bool) -> RequestBuilder;
+    // 解析一次性返回的完整回复
+    fn parse_response(&self, body: &Value) -> Result<Reply, ShellError>;
+    // 解析流式回复中的一个事件（SSE的data或者JSON Lines的一行）
+    fn parse_event(&self, event: &Value) -> Result<StreamEvent, ShellError>;
+    // 把工具的执行结果转换成加入对话的消息
+    fn tool_results(&self, results: &[(ToolCall, String)]) -> Vec<Value>;
+}
+
+pub const PROVIDERS: &[&str] = &["openai", "anthropic", "ollama"];
+
+// 按名称选择provider，没有指定时使用LLM_PROVIDER，默认为OpenAI兼容接口
+pub fn get(name: Option<&str>) -> Result<Box<dyn LlmProvider>, ShellError> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => default_name(),
+    };
+    match name.to_lowercase().as_str() {
+        "openai" => Ok(Box::new(OpenAi)),
+        "anthropic" => Ok(Box::new(Anthropic)),
+        "ollama" => Ok(Box::new(Ollama)),
+        _ => Err(ShellError::LLMError(format!(
+            "unknown provider '{}' (available: {})", name, PROVIDERS.join(", ")
+        ))),
+    }
+}
+
+pub fn default_name() -> String {
+    std::env::var("LLM_PROVIDER")
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "openai".to_string())
+        .to_lowercase()
+}
+
+// 各个接口的错误格式：{"error": {"message": ...}} 或者 {"error": "..."}
+pub fn error_message(body: &Value) -> Option<String> {
+    let error = body.get("error")?;
+    Some(error["message"].as_str()
+        .or_else(|| error.as_str())
+        .map_or_else(|| error.to_string(), String::from))
+}
+
+fn api_error(body: &Value) -> Option<ShellError> {
+    error_message(body).map(|message| ShellError::LLMError(format!("API Error: {}", message)))
+}
+
+// 回复中没有找到文本内容，通常是接口类型选错了
+fn unexpected_response(provider: &dyn LlmProvider, body: &Value) -> ShellError {
+    let mut body = body.to_string();
+    if let Some((end, _)) = body.char_indices().nth(200) {
+        body.truncate(end);
+        body.push('…');
+    }
+    ShellError::LLMError(format!(
+        "unexpected response for the {} API (is LLM_PROVIDER right?): {}", provider.name(), body
+    ))
+}
+
+// OpenAI chat completions以及兼容的接口（DeepSeek、OpenRouter、vLLM等）
+pub struct OpenAi;
+
+impl LlmProvider for OpenAi {
+    fn name(&self) -> &'static str {
+        "openai"
+    }
+
+    fn default_url(&self) -> &'static str {
+        "https://api.openai.com/v1/chat/completions"
+    }
+
+    fn request(&self, client: &Client, config: &Config, messages: &[Value], tools: &[Tool], stream: bool) -> RequestBuilder {
+        let mut payload = json!({
+            "model": config.model_name,
+            "messages": messages,
+            "stream": stream,
+        });
+        set_optional(&mut payload, "temperature", config.temperature);
+        set_optional(&mut payload, "top_p", config.top_p);
+        set_optional(&mut payload, "max_tokens", config.max_tokens);
+        if !tools.is_empty() {
+            payload["tools"] = function_tools(tools);
+        }
+
+        client.post(&config.api_url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, format!("Bearer {}", config.api_key))
+            .json(&payload)
+    }
+
+    // 请求工具调用时content为null，参数是JSON字符串
+    fn parse_response(&self, body: &Value) -> Result<Reply, ShellError> {
+        if let Some(error) = api_error(body) {
+            return Err(error);
+        }
+        let message = &body["choices"][0]["message"];
+        if !message.is_object() {
+            return Err(unexpected_response(self, body));
+        }
+
+        let tool_calls = message["tool_calls"].as_array().map_or_else(Vec::new, |calls| {
+            calls.iter().map(|call| ToolCall {
+                id: call["id"].as_str().unwrap_or_default().to_string(),
+                name: call["function"]["name"].as_str().unwrap_or_default().to_string(),
+                arguments: call["function"]["arguments"].as_str()
+                    .and_then(|arguments| serde_json::from_str(arguments).ok())
+                    .unwrap_or(Value::Null),
+            }).collect()
+        });
+        Ok(Reply {
+            text: message["content"].as_str().unwrap_or_default().to_string(),
+            tool_calls,
+            message: message.clone(),
+        })
+    }
+
+    // data: {"choices": [{"delta": {"content": ...}}]}，结束时是data: [DONE]
+    fn parse_event(&self, event: &Value) -> Result<StreamEvent, ShellError> {
+        if let Some(error) = api_error(event) {
+            return Err(error);
+        }
+        match event["choices"][0]["delta"]["content"].as_str() {
+            Some(delta) => Ok(StreamEvent::Text(delta.to_string())),
+            None => Ok(StreamEvent::Skip),
+        }
+    }
+
+    fn tool_results(&self, results: &[(ToolCall, String)]) -> Vec<Value> {
+        results.iter()
+            .map(|(call, output)| json!({ "role": "tool", "tool_call_id": call.id, "content": output }))
+            .collect()
+    }
+}
+
+// 只在设置了参数时加入请求，否则使用接口的默认值
+fn set_optional<T: Into<Value>>(payload: &mut Value, key: &str, value: Option<T>) {
+    if let Some(value) = value {
+        payload[key] = value.into();
+    }
+}
+
+// OpenAI和Ollama使用的工具格式
+fn function_tools(tools: &[Tool]) -> Value {
+    tools.iter().map(|tool| json!({
+        "type": "function",
+        "function": {
+            "name": tool.name,
+            "description": tool.description,
+            "parameters": tool.parameters,
+        },
+    })).collect()
+}
+
+// Anthropic Messages API
+pub struct Anthropic;
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+// Anthropic要求必须给出max_tokens
+const ANTHROPIC_MAX_TOKENS: u32 = 4096;
+
+impl LlmProvider for Anthropic {
+    fn name(&self) -> &'static str {
+        "anthropic"
+    }
+
+    fn default_url(&self) -> &'static str {
+        "https://api.anthropic.com/v1/messages"
+    }
+
+    // system消息不能放在messages中，需要合并到单独的system字段
+    fn request(&self, client: &Client, config: &Config, messages: &[Value], tools: &[Tool], stream: bool) -> RequestBuilder {
+        let (system, messages): (Vec<&Value>, Vec<&Value>) = messages.iter()
+            .partition(|message| message["role"] == "system");
+        let system: Vec<&str> = system.iter().filter_map(|message| message["content"].as_str()).collect();
+
+        let mut payload = json!({
+            "model": config.model_name,
+            "max_tokens": config.max_tokens.unwrap_or(ANTHROPIC_MAX_TOKENS),
+            "messages": messages,
+            "stream": stream,
+        });
+        set_optional(&mut payload, "temperature", config.temperature);
+        set_optional(&mut payload, "top_p", config.top_p);
+        if !system.is_empty() {
+            payload["system"] = json!(system.join("\n\n"));
+        }
+        if !tools.is_empty() {
+            payload["tools"] = tools.iter().map(|tool| json!({
+                "name": tool.name,
+                "description": tool.description,
+                "input_schema": tool.parameters,
+            })).collect();
+        }
+
+        client.post(&config.api_url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("x-api-key", &config.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(&payload)
+    }
+
+    // {"content": [{"type": "text", "text": ...}, {"type": "tool_use", "id": ..., "name": ..., "input": {...}}]}
+    fn parse_response(&self, body: &Value) -> Result<Reply, ShellError> {
+        if let Some(error) = api_error(body) {
+            return Err(error);
+        }
+        let blocks = body["content"].as_array()
+            .ok_or_else(|| unexpected_response(self, body))?;
+        Ok(Reply {
+            text: blocks.iter()
+                .filter(|block| block["type"] == "text")
+                .filter_map(|block| block["text"].as_str())
+                .collect(),
+            tool_calls: blocks.iter()
+                .filter(|block| block["type"] == "tool_use")
+                .map(|block| ToolCall {
+                    id: block["id"].as_str().unwrap_or_default().to_string(),
+                    name: block["name"].as_str().unwrap_or_default().to_string(),
+                    arguments: block["input"].clone(),
+                })
+                .collect(),
+            message: json!({ "role": "assistant", "content": blocks }),
+        })
+    }
+
+    // 只关心文本增量、结束和错误事件，message_start、ping等都忽略
+    fn parse_event(&self, event: &Value) -> Result<StreamEvent, ShellError> {
+        match event["type"].as_str() {
+            Some("content_block_delta") => match event["delta"]["text"].as_str() {
+                Some(text) => Ok(StreamEvent::Text(text.to_string())),
+                None => Ok(StreamEvent::Skip),
+            },
+            Some("message_stop") => Ok(StreamEvent::Done),
+            Some("error") => Err(api_error(event).unwrap_or_else(|| unexpected_response(self, event))),
+            _ => Ok(StreamEvent::Skip),
+        }
+    }
+
+    // 所有结果放在同一条user消息中
+    fn tool_results(&self, results: &[(ToolCall, String)]) -> Vec<Value> {
+        let content: Vec<Value> = results.iter()
+            .map(|(call, output)| json!({ "type": "tool_result", "tool_use_id": call.id, "content": output }))
+            .collect();
+        vec![json!({ "role": "user", "content": content })]
+    }
+}
+
+// 本地的Ollama，流式回复是每行一个JSON对象
+pub struct Ollama;
+
+impl LlmProvider for Ollama {
+    fn name(&self) -> &'static str {
+        "ollama"
+    }
+
+    fn default_url(&self) -> &'static str {
+        "http://localhost:11434/api/chat"
+    }
+
+    fn needs_key(&self) -> bool {
+        false
+    }
+
+    // 本地运行时不需要认证，放在反向代理后面时可以设置key
+    fn request(&self, client: &Client, config: &Config, messages: &[Value], tools: &[Tool], stream: bool) -> RequestBuilder {
+        let mut payload = json!({
+            "model": config.model_name,
+            "messages": messages,
+            "stream": stream,
+        });
+        // 采样参数放在options中，最大长度叫num_predict
+        let mut options = json!({});
+        set_optional(&mut options, "temperature", config.temperature);
+        set_optional(&mut options, "top_p", config.top_p);
+        set_optional(&mut options, "num_predict", config.max_tokens);
+        if options.as_object().is_some_and(|options| !options.is_empty()) {
+            payload["options"] = options;
+        }
+        if !tools.is_empty() {
+            payload["tools"] = function_tools(tools);
+        }
+
+        let request = client.post(&config.api_url)
+            .header(CONTENT_TYPE, "application/json")
+            .json(&payload);
+        if config.api_key.is_empty() {
+            request
+        } else {
+            request.header(AUTHORIZATION, format!("Bearer {}", config.api_key))
+        }
+    }
+
+    // 工具调用没有id，参数直接是JSON对象
+    fn parse_response(&self, body: &Value) -> Result<Reply, ShellError> {
+        if let Some(error) = api_error(body) {
+            return Err(error);
+        }
+        let message = &body["message"];
+        if !message.is_object() {
+            return Err(unexpected_response(self, body));
+        }
+
+        let tool_calls = message["tool_calls"].as_array().map_or_else(Vec::new, |calls| {
+            calls.iter().enumerate().map(|(i, call)| ToolCall {
+                id: format!("call_{}", i),
+                name: call["function"]["name"].as_str().unwrap_or_default().to_string(),
+                arguments: call["function"]["arguments"].clone(),
+            }).collect()
+        });
+        Ok(Reply {
+            text: message["content"].as_str().unwrap_or_default().to_string(),
+            tool_calls,
+            message: message.clone(),
+        })
+    }
+
+    // {"message": {"content": ...}, "done": false}，最后一行的done为true
+    fn parse_event(&self, event: &Value) -> Result<StreamEvent, ShellError> {
+        if let Some(error) = api_error(event) {
+            return Err(error);
+        }
+        if event["done"].as_bool() == Some(true) {
+            return Ok(StreamEvent::Done);
+        }
+        match event["message"]["content"].as_str() {
+            Some(text) => Ok(StreamEvent::Text(text.to_string())),
+            None => Ok(StreamEvent::Skip),
+        }
+    }
+
+    fn tool_results(&self, results: &[(ToolCall, String)]) -> Vec<Value> {
+        results.iter()
+            .map(|(call, output)| json!({ "role": "tool", "tool_name": call.name, "content": output }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{LazyLock, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::model_call::{llm_call, llm_call_tools, Params};
+
+    // 模拟服务器收到的请求，请求头的名称为小写
+    struct Request {
+        path: String,
+        headers: HashMap<String, String>,
+        body: Value,
+    }
+
+    // 测试以最后一条消息的内容作为名字，服务器按名字返回回复，这样并行的测试可以共用一个服务器
+    static REPLIES: LazyLock<Mutex<HashMap<String, (&'static str, String)>>> = LazyLock::new(Default::default);
+    static REQUESTS: LazyLock<Mutex<HashMap<String, Request>>> = LazyLock::new(Default::default);
+
+    // 启动模拟服务器，让每个provider的LLM_<PROVIDER>_API_URL指向它
+    static SERVER: LazyLock<()> = LazyLock::new(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || serve(stream));
+            }
+        });
+
+        // SAFETY: 只在这里设置一次，测试中的其他代码都通过std::env读取环境变量
+        unsafe {
+            for name in PROVIDERS {
+                env::set_var(format!("LLM_{}_API_URL", name.to_uppercase()), format!("{}/{}", url, name));
+            }
+            env::set_var("LLM_OPENAI_API_KEY", "openai-key");
+            env::set_var("LLM_ANTHROPIC_API_KEY", "anthropic-key");
+            env::remove_var("LLM_OLLAMA_API_KEY");
+            env::set_var("LLM_PROVIDER", "openai");
+            env::set_var("PSH_LLM_RETRIES", "0");
+            env::remove_var("PSH_CHAT_STREAM");
+        }
+    });
+
+    fn serve(mut stream: TcpStream) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let Some((name, value)) = line.trim_end().split_once(':') else { break };
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+        let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        let name = body["messages"].as_array()
+            .and_then(|messages| messages.last())
+            .and_then(|message| message["content"].as_str())
+            .unwrap_or_default()
+            .to_string();
+        let (content_type, reply) = REPLIES.lock().unwrap().get(&name).cloned().unwrap_or(("text/plain", String::new()));
+        REQUESTS.lock().unwrap().insert(name, Request { path, headers, body });
+
+        // 没有Content-Length，关闭连接表示结束；分成小块发送，使行和UTF-8字符被分在不同的块中
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nConnection: close\r\n\r\n", content_type).unwrap();
+        for piece in reply.as_bytes().chunks(7) {
+            if stream.write_all(piece).and_then(|_| stream.flush()).is_err() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn params() -> Params {
+        Params {
+            model: Some("test-model".to_string()),
+            temperature: Some(0.5),
+            max_tokens: Some(100),
+            top_p: Some(0.9),
+            system: Some("Be brief.".to_string()),
+            ..Params::default()
+        }
+    }
+
+    fn tool() -> Tool {
+        Tool { name: "run_command", description: "Run a command".to_string(), parameters: json!({ "type": "object" }) }
+    }
+
+    fn reply_with(name: &str, content_type: &'static str, reply: &str) {
+        LazyLock::force(&SERVER);
+        REPLIES.lock().unwrap().insert(name.to_string(), (content_type, reply.to_string()));
+    }
+
+    fn received(name: &str) -> Request {
+        REQUESTS.lock().unwrap().remove(name).expect("the server received no request")
+    }
+
+    // 流式调用，返回结果、收到的每一段和服务器收到的请求
+    fn stream(provider: &str, name: &str, content_type: &'static str, reply: &str) -> (Result<String, ShellError>, Vec<String>, Request) {
+        reply_with(name, content_type, reply);
+        let provider = get(Some(provider)).unwrap();
+        let mut deltas = Vec::new();
+        let messages = vec![json!({ "role": "user", "content": name })];
+        let result = llm_call(provider.as_ref(), &params(), messages, &mut |delta| {
+            deltas.push(delta.to_string());
+            Ok(())
+        });
+        (result, deltas, received(name))
+    }
+
+    // 带工具的一次性调用
+    fn complete(provider: &dyn LlmProvider, name: &str, reply: &Value) -> (Reply, Request) {
+        reply_with(name, "application/json", &reply.to_string());
+        let messages = [json!({ "role": "user", "content": name })];
+        let reply = llm_call_tools(provider, &params(), &messages, &[tool()]).unwrap_or_else(|e| panic!("{}", e));
+        (reply, received(name))
+    }
+
+    fn results(reply: Reply) -> Vec<(ToolCall, String)> {
+        reply.tool_calls.into_iter().map(|call| (call, "output".to_string())).collect()
+    }
+
+    #[test]
+    fn openai_request_and_response() {
+        let reply = json!({ "choices": [{ "message": {
+            "role": "assistant",
+            "content": "Checking.",
+            "tool_calls": [{ "id": "call_1", "type": "function", "function": { "name": "run_command", "arguments": "{\"command\":\"ls\"}" } }],
+        } }] });
+        let (reply, request) = complete(&OpenAi, "openai complete", &reply);
+
+        assert_eq!(request.path, "/openai");
+        assert_eq!(request.headers["authorization"], "Bearer openai-key");
+        assert_eq!(request.headers["content-type"], "application/json");
+        let body = &request.body;
+        assert_eq!(body["model"], "test-model");
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["top_p"], 0.9);
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["messages"], json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": "openai complete" },
+        ]));
+        assert_eq!(body["tools"][0]["type"], "function");
+        assert_eq!(body["tools"][0]["function"]["name"], "run_command");
+        assert_eq!(body["tools"][0]["function"]["parameters"], json!({ "type": "object" }));
+
+        assert_eq!(reply.text, "Checking.");
+        assert_eq!(reply.tool_calls.len(), 1);
+        assert_eq!(reply.tool_calls[0].id, "call_1");
+        assert_eq!(reply.tool_calls[0].name, "run_command");
+        assert_eq!(reply.tool_calls[0].arguments, json!({ "command": "ls" }));
+        assert_eq!(reply.message["tool_calls"][0]["id"], "call_1");
+        assert_eq!(OpenAi.tool_results(&results(reply)), [json!({ "role": "tool", "tool_call_id": "call_1", "content": "output" })]);
+    }
+
+    #[test]
+    fn openai_stream() {
+        let events = concat!(
+            ": keep-alive\n\n",
+            "data: {\"choices\":[{\"delta\":{\"role\":\"assistant\"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hello, \"}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"wörld 🌍\"}}]}\n\n",
+            "data: [DONE]\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"ignored\"}}]}\n\n",
+        );
+        let (result, deltas, request) = stream("openai", "openai stream", "text/event-stream", events);
+        assert_eq!(result.unwrap(), "Hello, wörld 🌍");
+        assert_eq!(deltas, ["Hello, ", "wörld 🌍"]);
+        assert_eq!(request.body["stream"], true);
+        assert!(request.body.get("tools").is_none());
+    }
+
+    #[test]
+    fn openai_stream_error() {
+        let events = concat!(
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Partial\"}}]}\n\n",
+            "data: {\"error\":{\"message\":\"The server is overloaded\"}}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"ignored\"}}]}\n\n",
+        );
+        let (result, deltas, _) = stream("openai", "openai stream error", "text/event-stream", events);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("The server is overloaded"), "{}", error);
+        assert_eq!(deltas, ["Partial"]);
+    }
+
+    #[test]
+    fn anthropic_request_and_response() {
+        let reply = json!({ "content": [
+            { "type": "text", "text": "Let me check." },
+            { "type": "tool_use", "id": "toolu_1", "name": "run_command", "input": { "command": "pwd" } },
+        ], "stop_reason": "tool_use" });
+        let (reply, request) = complete(&Anthropic, "anthropic complete", &reply);
+
+        assert_eq!(request.path, "/anthropic");
+        assert_eq!(request.headers["x-api-key"], "anthropic-key");
+        assert_eq!(request.headers["anthropic-version"], ANTHROPIC_VERSION);
+        assert!(!request.headers.contains_key("authorization"));
+        let body = &request.body;
+        assert_eq!(body["model"], "test-model");
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["max_tokens"], 100);
+        assert_eq!(body["temperature"], 0.5);
+        assert_eq!(body["top_p"], 0.9);
+        assert_eq!(body["system"], "Be brief.");
+        assert_eq!(body["messages"], json!([{ "role": "user", "content": "anthropic complete" }]));
+        assert_eq!(body["tools"], json!([{ "name": "run_command", "description": "Run a command", "input_schema": { "type": "object" } }]));
+
+        assert_eq!(reply.text, "Let me check.");
+        assert_eq!(reply.tool_calls.len(), 1);
+        assert_eq!(reply.tool_calls[0].id, "toolu_1");
+        assert_eq!(reply.tool_calls[0].arguments, json!({ "command": "pwd" }));
+        assert_eq!(reply.message["content"][1]["type"], "tool_use");
+        assert_eq!(Anthropic.tool_results(&results(reply)), [json!({
+            "role": "user",
+            "content": [{ "type": "tool_result", "tool_use_id": "toolu_1", "content": "output" }],
+        })]);
+    }
+
+    #[test]
+    fn anthropic_stream() {
+        let events = concat!(
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"id\":\"msg_1\"}}\n\n",
+            "event: content_block_start\ndata: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+            "event: ping\ndata: {\"type\":\"ping\"}\n\n",
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi \"}}\n\n",
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"thére\"}}\n\n",
+            "event: content_block_stop\ndata: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+            "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"ignored\"}}\n\n",
+        );
+        let (result, deltas, request) = stream("anthropic", "anthropic stream", "text/event-stream", events);
+        assert_eq!(result.unwrap(), "Hi thére");
+        assert_eq!(deltas, ["Hi ", "thére"]);
+        assert_eq!(request.body["stream"], true);
+    }
+
+    #[test]
+    fn anthropic_stream_error() {
+        let events = concat!(
+            "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Partial\"}}\n\n",
+            "event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n",
+        );
+        let (result, deltas, _) = stream("anthropic", "anthropic stream error", "text/event-stream", events);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Overloaded"), "{}", error);
+        assert_eq!(deltas, ["Partial"]);
+    }
+
+    #[test]
+    fn ollama_request_and_response() {
+        let reply = json!({ "message": {
+            "role": "assistant",
+            "content": "",
+            "tool_calls": [{ "function": { "name": "run_command", "arguments": { "command": "df -h" } } }],
+        }, "done": true });
+        let (reply, request) = complete(&Ollama, "ollama complete", &reply);
+
+        assert_eq!(request.path, "/ollama");
+        assert!(!request.headers.contains_key("authorization"));
+        let body = &request.body;
+        assert_eq!(body["model"], "test-model");
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["options"], json!({ "temperature": 0.5, "top_p": 0.9, "num_predict": 100 }));
+        assert!(body.get("temperature").is_none() && body.get("max_tokens").is_none());
+        assert_eq!(body["messages"][0], json!({ "role": "system", "content": "Be brief." }));
+        assert_eq!(body["tools"][0]["function"]["name"], "run_command");
+
+        assert_eq!(reply.text, "");
+        assert_eq!(reply.tool_calls.len(), 1);
+        assert_eq!(reply.tool_calls[0].id, "call_0");
+        assert_eq!(reply.tool_calls[0].arguments, json!({ "command": "df -h" }));
+        assert_eq!(Ollama.tool_results(&results(reply)), [json!({ "role": "tool", "tool_name": "run_command", "content": "output" })]);
+    }
+
+    #[test]
+    fn ollama_stream() {
+        let lines = concat!(
+            "{\"model\":\"test-model\",\"message\":{\"role\":\"assistant\",\"content\":\"Hel\"},\"done\":false}\n",
+            "{\"model\":\"test-model\",\"message\":{\"role\":\"assistant\",\"content\":\"lo ✓\"},\"done\":false}\n",
+            "{\"model\":\"test-model\",\"message\":{\"role\":\"assistant\",\"content\":\"\"},\"done\":true,\"done_reason\":\"stop\"}\n",
+            "{\"message\":{\"content\":\"ignored\"},\"done\":false}\n",
+        );
+        let (result, deltas, request) = stream("ollama", "ollama stream", "application/x-ndjson", lines);
+        assert_eq!(result.unwrap(), "Hello ✓");
+        assert_eq!(deltas, ["Hel", "lo ✓"]);
+        assert_eq!(request.body["stream"], true);
+    }
+
+    #[test]
+    fn ollama_stream_error() {
+        let lines = concat!(
+            "{\"message\":{\"role\":\"assistant\",\"content\":\"Partial\"},\"done\":false}\n",
+            "{\"error\":\"model 'test-model' not found\"}\n",
+        );
+        let (result, deltas, _) = stream("ollama", "ollama stream error", "application/x-ndjson", lines);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("model 'test-model' not found"), "{}", error);
+        assert_eq!(deltas, ["Partial"]);
+    }
+}
@@ -1,28 +1,138 @@
 use std::thread;
 use std::process::{Stdio, exit};
-use std::io::{self, Read, Write};
-use std::fs::File;
-use rustyline::DefaultEditor;
+use std::os::unix::process::ExitStatusExt;
+use std::cell::Cell;
+use std::io::{self, IsTerminal, Read, Write};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
 use os_pipe::{pipe, PipeReader, PipeWriter};
 
 use crate::builtins;
-use crate::error::ShellError;
+use crate::completion::PshEditor;
+use crate::highlight::RightPrompt;
+use crate::history;
+use crate::last_error;
+use crate::keybind;
+use crate::error::{self, ShellError};
 use crate::executor::execute;
 use crate::parser::{parse_line, Command};
-use crate::args_analysis::redirection_analysis;
+use crate::prompt::{self, PromptContext};
 
+// 正在运行的后台任务数
+static RUNNING_JOBS: AtomicUsize = AtomicUsize::new(0);
+
+// 下一次读取输入时预先填入编辑器的内容，由ask等builtin设置
+static PENDING_LINE: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_pending_line(line: &str) {
+    *PENDING_LINE.lock().unwrap() = Some(line.to_string());
+}
+
+// 为模型执行的命令记录它启动的外部命令的pid，超时后结束它们
+// 这些外部命令的标准输入为空，不会读取终端
+type LimitedChildren = Arc<Mutex<Vec<u32>>>;
+
+// 终端的列数，每次显示提示符时更新，0表示未知
+static TERMINAL_COLUMNS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn terminal_columns() -> Option<usize> {
+    Some(TERMINAL_COLUMNS.load(Ordering::SeqCst)).filter(|&columns| columns > 0)
+}
+
+thread_local! {
+    // 当前线程正在执行的内建命令的输出是否直接写到终端（没有管道和重定向）
+    static OUTPUT_IS_TERMINAL: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn output_is_terminal() -> bool {
+    OUTPUT_IS_TERMINAL.with(Cell::get)
+}
+
+pub fn job_count() -> usize {
+    RUNNING_JOBS.load(Ordering::SeqCst)
+}
 
 // 主循环的功能是，不断接受输入调用handle_command解析命令，并处理Ctrl+C Ctrl+D
-pub fn main_loop(mut reader: DefaultEditor) {
+pub fn main_loop(mut reader: PshEditor) {
+    let mut last_status = 0;
+    let mut last_duration = Duration::ZERO;
+
     loop {
-        let read_result = reader.readline(&crate::prompt::get_prompt());
+        // 应用set -o和bind做出的修改
+        keybind::apply(&mut reader);
+
+        let ctx = PromptContext::new(last_status, last_duration, job_count());
+        let prompt = prompt::get_prompt(&ctx);
+        // 不是终端时没有宽度，也就不显示右侧提示符和transient提示符
+        let columns = reader.dimensions().map(|(columns, _)| columns as usize);
+        TERMINAL_COLUMNS.store(columns.unwrap_or(0), Ordering::SeqCst);
+        if let Some(helper) = reader.helper() {
+            helper.set_right_prompt(columns.and_then(|columns| {
+                prompt::get_right_prompt(&ctx).map(|text| RightPrompt::new(&text, &prompt, columns))
+            }));
+        }
+
+        let pending = PENDING_LINE.lock().unwrap().take();
+        let read_result = match pending {
+            Some(pending) => reader.readline_with_initial(&prompt, (&pending, "")),
+            None => reader.readline(&prompt),
+        };
+        if let Some(helper) = reader.helper() {
+            helper.set_right_prompt(None);
+        }
 
         match read_result {
             Ok(line) => {
+                if let Some(columns) = columns
+                    && let Some(transient) = prompt::get_transient_prompt(&ctx)
+                {
+                    let display = reader.helper().map_or(line.clone(), |h| h.highlight(&line, line.len()).into_owned());
+                    prompt::collapse_prompt(&prompt, &transient, &line, &display, columns);
+                }
+
+                // 在parse之前做历史展开，展开后回显实际执行的命令
+                let line = match history::expand(&line) {
+                    Ok(Some(expanded)) => {
+                        println!("{}", expanded);
+                        expanded
+                    }
+                    Ok(None) => line,
+                    Err(e) => {
+                        error::report(e);
+                        last_status = 1;
+                        last_duration = Duration::ZERO;
+                        continue;
+                    }
+                };
+
                 reader.add_history_entry(line.as_str())
                     .expect("Failed to add history");
-                handle_command(parse_line(&line), None, None);
+                let entry = history::push(&line);
+                let start = Instant::now();
+                last_error::clear_stderr();
+                last_status = handle_command(parse_line(&line), None, None);
+                last_duration = start.elapsed();
+                // why自己失败时不覆盖要解释的那条命令
+                if !is_explain_command(&line) {
+                    last_error::finish(&line, last_status);
+                }
+                if let Some(entry) = entry {
+                    history::set_status(entry, last_status);
+                }
+
+                // history -c / -d 修改了历史，同步到rustyline
+                if history::take_changed() {
+                    reader.clear_history().expect("Failed to clear history");
+                    for entry in history::entries() {
+                        reader.add_history_entry(entry.line.as_str())
+                            .expect("Failed to add history");
+                    }
+                }
             }
 
             // Ctrl + C
@@ -40,38 +150,124 @@ pub fn main_loop(mut reader: DefaultEditor) {
     }
 }
 
+// 是否是解释上一条失败命令的why或chat --last-error
+fn is_explain_command(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("why") => true,
+        Some("chat") => words.any(|w| w == "--last-error"),
+        _ => false,
+    }
+}
+
+/// 逐行执行一个文件中的命令，忽略空行和#开头的注释
+pub fn source_file(path: &Path) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            error::report(format!("Failed to read '{}': {}", path.display(), e));
+            return;
+        }
+    };
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        handle_command(parse_line(line), None, None);
+    }
+}
+
+/// 执行一行命令并捕获它的标准输出
+pub fn capture_output(line: &str) -> Result<String, ShellError> {
+    capture_command(line).map(|(output, _)| output)
+}
+
+/// 执行一行命令，返回捕获的标准输出和退出状态，不是UTF-8的内容按替换字符读入
+pub fn capture_command(line: &str) -> Result<(String, i32), ShellError> {
+    capture_with(line, None)
+}
+
+fn capture_with(line: &str, limited: Option<LimitedChildren>) -> Result<(String, i32), ShellError> {
+    let command = parse_line(line)?;
+    let (mut pipe_reader, pipe_writer) = pipe()?;
+
+    // 在另一个线程执行命令，命令结束后pipe_writer被drop，读取端才会结束
+    let handle = thread::spawn(move || {
+        run_command(Ok(command), None, Some(pipe_writer), limited)
+    });
+
+    let mut output = Vec::new();
+    pipe_reader.read_to_end(&mut output)?;
+    let status = handle.join().map_err(|_| ShellError::ExecuteError("Failed to join handle".to_string()))?;
+
+    Ok((String::from_utf8_lossy(&output).into_owned(), status))
+}
+
+/// 执行模型请求的命令：外部命令的标准输入为空，超过timeout后结束仍在运行的外部命令
+/// 返回捕获的标准输出、退出状态和是否超时
+pub fn capture_command_limited(line: &str, timeout: Duration) -> Result<(String, i32, bool), ShellError> {
+    let children = LimitedChildren::default();
+    let (sender, receiver) = mpsc::channel();
+    let line = line.to_string();
+    let limited = children.clone();
+    thread::spawn(move || {
+        let _ = sender.send(capture_with(&line, Some(limited)));
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result.map(|(output, status)| (output, status, false)),
+        Err(_) => {
+            for &pid in children.lock().unwrap().iter() {
+                // SAFETY: kill只是发送信号，不涉及内存
+                unsafe {
+                    libc::kill(pid as libc::pid_t, libc::SIGKILL);
+                }
+            }
+            // 子进程再启动的进程可能还占用着管道，稍等之后放弃
+            match receiver.recv_timeout(Duration::from_secs(1)) {
+                Ok(result) => result.map(|(output, status)| (output, status, true)),
+                Err(_) => Err(ShellError::ExecuteError(format!("command did not finish within {} seconds", timeout.as_secs()))),
+            }
+        }
+    }
+}
+
 // input 和 output 表示命令的输入输出流
 // 如果默认用标准流输入输出（而不Pipe设置的流）的话，二者会被设置为None
-fn handle_command(
+// 返回值是命令的退出状态，0表示成功
+pub fn handle_command(
     cmd: Result<Command, ShellError>,
     input: Option<PipeReader>,
     output: Option<PipeWriter>,
-) {
+) -> i32 {
+    run_command(cmd, input, output, None)
+}
+
+// limited不为None时命令是为模型执行的，启动的外部命令记录在其中
+fn run_command(
+    cmd: Result<Command, ShellError>,
+    input: Option<PipeReader>,
+    output: Option<PipeWriter>,
+    limited: Option<LimitedChildren>,
+) -> i32 {
     match cmd {
-        Ok(Command::Empty) => return,
+        Ok(Command::Empty) => 0,
         Ok(Command::Exit) => {
             println!("Exiting...");
             exit(0);
         }
 
-        Ok(Command::Builtin(cmd, mut args)) => {
-            // 分析重定向符号
-            let redirection = match redirection_analysis(&mut args) {
-                Ok(r) => r,
-                Err(e) => {
-                    eprintln!("psh: {}", e);
-                    return;
-                }
-            };
-
-            // 处理输入
+        Ok(Command::Builtin(cmd, args, redirection)) => {
+            // 处理输入，不是UTF-8的内容（如二进制文件）按替换字符读入
             let mut piped_input = if let Some(mut pipe_reader) = input {
                 // 从管道读取
-                let mut buffer = String::new();
-                match pipe_reader.read_to_string(&mut buffer) {
-                    Ok(_) => Some(buffer),
+                let mut buffer = Vec::new();
+                match pipe_reader.read_to_end(&mut buffer) {
+                    Ok(_) => Some(String::from_utf8_lossy(&buffer).into_owned()),
                     Err(e) => {
-                        eprintln!("psh: Failed to read from pipe: {}", e);
+                        error::report(format!("Failed to read from pipe: {}", e));
                         None
                     }
                 }
@@ -81,7 +277,7 @@ fn handle_command(
 
             // 如果有输入重定向，读取文件内容
             if let Some(input_file) = redirection.input_file {
-                match std::fs::read_to_string(&input_file) {
+                match std::fs::read(&input_file).map(|bytes| String::from_utf8_lossy(&bytes).into_owned()) {
                     Ok(content) => {
                         // 如果已经有管道输入，合并它们；否则直接使用文件内容
                         if let Some(ref mut pipe_data) = piped_input {
@@ -91,20 +287,21 @@ fn handle_command(
                         }
                     }
                     Err(e) => {
-                        eprintln!("psh: Failed to read input file '{}': {}", input_file, e);
-                        return;
+                        error::report(format!("Failed to read input file '{}': {}", input_file, e));
+                        return 1;
                     }
                 }
             }
 
             // 处理输出
+            let to_terminal = output.is_none() && redirection.output_file.is_none() && io::stdout().is_terminal();
             let mut writer: Box<dyn Write> = if let Some(output_file) = redirection.output_file {
                 // 输出重定向到文件
                 match File::create(&output_file) {
                     Ok(file) => Box::new(file),
                     Err(e) => {
-                        eprintln!("psh: Failed to create output file '{}': {}", output_file, e);
-                        return;
+                        error::report(format!("Failed to create output file '{}': {}", output_file, e));
+                        return 1;
                     }
                 }
             } else {
@@ -112,6 +309,7 @@ fn handle_command(
                 output.map_or(Box::new(io::stdout()), |p| Box::new(p))
             };
 
+            OUTPUT_IS_TERMINAL.with(|cell| cell.set(to_terminal));
             let result = match cmd.as_str() {
                 "cd" => builtins::builtin_cd(args, piped_input, &mut *writer),
                 "pwd" => builtins::builtin_pwd(args, piped_input, &mut *writer),
@@ -119,27 +317,58 @@ fn handle_command(
                 "ls" => builtins::builtin_ls(args, piped_input, &mut *writer),
                 "grep" => builtins::builtin_grep(args, piped_input, &mut *writer),
                 "chat" => builtins::builtin_model_call(args, piped_input, &mut *writer),
-                _ => return,
+                "history" => builtins::builtin_history(args, piped_input, &mut *writer),
+                "complete" => builtins::builtin_complete(args, piped_input, &mut *writer),
+                "set" => builtins::builtin_set(args, piped_input, &mut *writer),
+                "bind" => builtins::builtin_bind(args, piped_input, &mut *writer),
+                "theme" => builtins::builtin_theme(args, piped_input, &mut *writer),
+                "ask" => builtins::builtin_ask(args, piped_input, &mut *writer),
+                "why" => builtins::builtin_why(args, piped_input, &mut *writer),
+                _ => return 1,
             };
+            OUTPUT_IS_TERMINAL.with(|cell| cell.set(false));
 
-            if let Err(e) = result {
-                eprintln!("psh: {}", e);
+            match result {
+                Ok(()) => 0,
+                Err(e) => {
+                    error::report(e);
+                    1
+                }
             }
         }
 
         Ok(Command::External(program, args)) => {
             // 解析input和output。如果是None，map_or会父进程的io流，实际上就是Stdio
-            let stdin = input.map_or(Stdio::inherit(), Stdio::from);
+            let stdin = input.map_or_else(|| if limited.is_some() { Stdio::null() } else { Stdio::inherit() }, Stdio::from);
             let stdout = output.map_or(Stdio::inherit(), Stdio::from);
+            // 开启捕获时通过管道读取标准错误输出，再转发到终端
+            let stderr = if last_error::capture_enabled() { Stdio::piped() } else { Stdio::inherit() };
 
-            match execute(&program, args, stdin, stdout) {
+            match execute(&program, args, stdin, stdout, stderr) {
                 Ok(mut child) => {
-                    if let Err(e) = child.wait() {
-                        eprintln!("psh: failed to wait on process: {}", e);
+                    if let Some(children) = &limited {
+                        children.lock().unwrap().push(child.id());
+                    }
+                    let tee = child.stderr.take().map(last_error::tee);
+                    let status = child.wait();
+                    if let Some(children) = &limited {
+                        children.lock().unwrap().retain(|&pid| pid != child.id());
+                    }
+                    if let Some(tee) = tee {
+                        let _ = tee.join();
+                    }
+                    match status {
+                        // 被信号终止时按照惯例返回128+信号值
+                        Ok(status) => status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+                        Err(e) => {
+                            error::report(format!("failed to wait on process: {}", e));
+                            1
+                        }
                     }
                 }
                 Err(e) => {
-                    eprintln!("psh: {}", e);
+                    error::report(e);
+                    127
                 }
             }
         }
@@ -147,26 +376,53 @@ fn handle_command(
             // 直接生成一个子进程递归调用handle_command但是不等待。
             // 如果内部的Command是External，那么子进程会生成另一个子进程用来执行命令。
             // 这实际上造成了进程冗余，但是为了设计简洁姑且如此。
+            RUNNING_JOBS.fetch_add(1, Ordering::SeqCst);
             thread::spawn(move || {
-                handle_command(Ok(*boxed_command), input, output);
+                run_command(Ok(*boxed_command), input, output, limited);
+                RUNNING_JOBS.fetch_sub(1, Ordering::SeqCst);
             });
+            0
         }
         Ok(Command::Pipe(former_command, latter_command)) => {
             let (pipe_reader, pipe_writer) = pipe().expect("psh: Failed to create pipe");
 
+            let former_limited = limited.clone();
             let handle1 = thread::spawn(||{
-                handle_command(Ok(*former_command), input, Some(pipe_writer));
+                run_command(Ok(*former_command), input, Some(pipe_writer), former_limited);
             });
 
             let handle2 = thread::spawn(||{
-                handle_command(Ok(*latter_command), Some(pipe_reader), output);
+                run_command(Ok(*latter_command), Some(pipe_reader), output, limited)
             });
 
+            // 管道的退出状态是最后一个命令的退出状态
             handle1.join().expect("psh: Failed to join handle");
-            handle2.join().expect("psh: Failed to join handle");
+            handle2.join().expect("psh: Failed to join handle")
         }
         Err(e) => {
-            eprintln!("psh: {}", e);
+            error::report(e);
+            2
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limited_commands_get_empty_stdin() {
+        let (output, status, timed_out) = capture_command_limited("cat | wc -c", Duration::from_secs(10)).unwrap();
+        assert_eq!((output.trim(), status, timed_out), ("0", 0, false));
+    }
+
+    #[test]
+    fn kills_limited_commands_after_timeout() {
+        let start = Instant::now();
+        let (output, status, timed_out) = capture_command_limited("sh -c 'echo started; exec sleep 30'", Duration::from_millis(300)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(output, "started\n");
+        assert_eq!(status, 128 + libc::SIGKILL);
+        assert!(timed_out);
+    }
+}
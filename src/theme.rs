@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{LazyLock, RwLock};
+use colorgrad::{Color, GradientBuilder, LinearGradient};
+use serde_json::Value;
+
+use crate::config;
+use crate::error::{self, ShellError};
+
+// 提示符中可以单独设置渐变色的部分，default用于没有单独设置的部分
+pub const PROMPT_SEGMENTS: &[&str] = &["default", "user", "host", "dir", "time", "status", "jobs", "git", "duration"];
+// 语法高亮中可以设置颜色的元素
+pub const HIGHLIGHT_ELEMENTS: &[&str] = &["command", "unknown", "builtin", "string", "operator", "redirection", "variable", "hint"];
+
+// 内建主题：(名字, 提示符渐变, Banner渐变, 高亮颜色)
+// 提示符渐变以(部分, 颜色节点)的形式给出，高亮颜色的顺序与HIGHLIGHT_ELEMENTS一致
+type ThemeData = (&'static str, &'static [(&'static str, &'static [&'static str])], &'static [&'static str], [&'static str; 8]);
+
+const BUILTIN_THEMES: &[ThemeData] = &[
+    (
+        "rainbow",
+        &[
+            ("default", &["#FF6B6B", "#FFA07A", "#FFD93D", "#6BCF7F", "#4ECDC4", "#45B7D1", "#9B59B6", "#E056FD"]),
+            ("status", &["#FF3B3B"]),
+            ("duration", &["#FFD93D", "#FFA07A"]),
+        ],
+        &["#0000FF", "#F000FF"],
+        ["#6BCF7F", "#FF6B6B", "#45B7D1", "#FFD93D", "#E056FD", "#4ECDC4", "#FFA07A", "#808080"],
+    ),
+    (
+        "ocean",
+        &[
+            ("default", &["#00C9FF", "#92FE9D"]),
+            ("user", &["#2E3192", "#1BFFFF"]),
+            ("time", &["#4FACFE", "#00F2FE"]),
+            ("status", &["#FF416C", "#FF4B2B"]),
+            ("git", &["#A18CD1", "#FBC2EB"]),
+        ],
+        &["#2E3192", "#1BFFFF"],
+        ["#92FE9D", "#FF416C", "#1BFFFF", "#F9D423", "#4FACFE", "#00F2FE", "#A18CD1", "#5F7A8A"],
+    ),
+    (
+        "forest",
+        &[
+            ("default", &["#71B280", "#DCE35B"]),
+            ("user", &["#A8E063", "#56AB2F"]),
+            ("time", &["#134E5E", "#71B280"]),
+            ("status", &["#E74C3C", "#D35400"]),
+            ("git", &["#F4D03F", "#E67E22"]),
+        ],
+        &["#134E5E", "#71B280"],
+        ["#A8E063", "#E74C3C", "#56AB2F", "#F4D03F", "#D35400", "#16A085", "#E67E22", "#6B7B6B"],
+    ),
+    (
+        "sunset",
+        &[
+            ("default", &["#F83600", "#F9D423"]),
+            ("dir", &["#FF512F", "#DD2476"]),
+            ("status", &["#FF0844", "#FFB199"]),
+            ("git", &["#FC6767", "#EC008C"]),
+        ],
+        &["#FF512F", "#DD2476"],
+        ["#F9D423", "#FF0844", "#FF9966", "#FFD86F", "#DD2476", "#FF5E62", "#FC6767", "#8A7070"],
+    ),
+    (
+        "mono",
+        &[
+            ("default", &["#FFFFFF", "#888888"]),
+            ("status", &["#FF5555"]),
+        ],
+        &["#FFFFFF", "#666666"],
+        ["#FFFFFF", "#FF5555", "#CCCCCC", "#AAAAAA", "#DDDDDD", "#BBBBBB", "#EEEEEE", "#666666"],
+    ),
+];
+
+const DEFAULT_THEME: &str = "rainbow";
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub name: String,
+    // 部分 -> 渐变色节点
+    prompt: HashMap<String, Vec<Color>>,
+    banner: Vec<Color>,
+    // 元素 -> 颜色
+    highlight: HashMap<String, Color>,
+}
+
+fn parse_color(text: &str) -> Result<Color, ShellError> {
+    Color::from_html(text).map_err(|e| ShellError::BuiltinError(format!("theme: invalid color '{}': {}", text, e)))
+}
+
+// 主题加载时已经保证颜色列表非空，构建失败时退回白色
+fn build_gradient(colors: &[Color]) -> LinearGradient {
+    GradientBuilder::new()
+        .colors(colors)
+        .build::<LinearGradient>()
+        .unwrap_or_else(|_| GradientBuilder::new().html_colors(&["#FFFFFF"]).build().unwrap())
+}
+
+impl Theme {
+    fn from_data((name, prompt, banner, highlight): &ThemeData) -> Theme {
+        let parse_all = |colors: &[&str]| colors.iter().map(|c| parse_color(c).unwrap()).collect::<Vec<_>>();
+        Theme {
+            name: name.to_string(),
+            prompt: prompt.iter().map(|(segment, colors)| (segment.to_string(), parse_all(colors))).collect(),
+            banner: parse_all(banner),
+            highlight: HIGHLIGHT_ELEMENTS.iter()
+                .zip(highlight.iter())
+                .map(|(element, color)| (element.to_string(), parse_color(color).unwrap()))
+                .collect(),
+        }
+    }
+
+    // 从用户主题文件解析主题，没有给出的部分沿用默认主题
+    // {
+    //   "prompt": { "default": ["#FF0000", "#0000FF"], "dir": ["#00FF00", "#00FFFF"] },
+    //   "banner": ["#0000FF", "#FF00FF"],
+    //   "highlight": { "command": "#00FF00", "unknown": "#FF0000" }
+    // }
+    fn from_json(name: &str, json: &Value) -> Result<Theme, ShellError> {
+        let mut theme = builtin(DEFAULT_THEME).unwrap();
+        theme.name = name.to_string();
+
+        let parse_list = |value: &Value| -> Result<Vec<Color>, ShellError> {
+            let colors = value.as_array()
+                .filter(|colors| !colors.is_empty())
+                .ok_or_else(|| ShellError::BuiltinError(format!("theme {}: gradient must be a non-empty list of colors", name)))?;
+            colors.iter()
+                .map(|c| parse_color(c.as_str().unwrap_or_default()))
+                .collect()
+        };
+
+        if let Some(prompt) = json["prompt"].as_object() {
+            for (segment, colors) in prompt {
+                theme.prompt.insert(segment.clone(), parse_list(colors)?);
+            }
+        }
+        if !json["banner"].is_null() {
+            theme.banner = parse_list(&json["banner"])?;
+        }
+        if let Some(highlight) = json["highlight"].as_object() {
+            for (element, color) in highlight {
+                theme.highlight.insert(element.clone(), parse_color(color.as_str().unwrap_or_default())?);
+            }
+        }
+
+        Ok(theme)
+    }
+
+    /// 提示符某一部分的渐变色
+    pub fn gradient(&self, segment: &str) -> LinearGradient {
+        let colors = self.prompt.get(segment)
+            .or_else(|| self.prompt.get("default"))
+            .map_or(&[][..], Vec::as_slice);
+        build_gradient(colors)
+    }
+
+    pub fn banner_gradient(&self) -> LinearGradient {
+        build_gradient(&self.banner)
+    }
+
+    /// 语法高亮元素的颜色
+    pub fn highlight_color(&self, element: &str) -> (u8, u8, u8) {
+        let rgba = self.highlight.get(element).map_or([255, 255, 255, 255], Color::to_rgba8);
+        (rgba[0], rgba[1], rgba[2])
+    }
+}
+
+fn builtin(name: &str) -> Option<Theme> {
+    BUILTIN_THEMES.iter().find(|data| data.0 == name).map(Theme::from_data)
+}
+
+// 用户主题目录 ~/.config/psh/themes/，每个主题是一个NAME.json文件
+fn themes_dir() -> PathBuf {
+    config::config_dir().join("themes")
+}
+
+/// 按名字加载主题，用户主题优先于同名的内建主题
+pub fn load(name: &str) -> Result<Theme, ShellError> {
+    let path = themes_dir().join(format!("{}.json", name));
+    if path.is_file() {
+        let content = fs::read_to_string(&path)?;
+        let json: Value = serde_json::from_str(&content)
+            .map_err(|e| ShellError::BuiltinError(format!("theme: {}: {}", path.display(), e)))?;
+        return Theme::from_json(name, &json);
+    }
+
+    builtin(name).ok_or_else(|| ShellError::BuiltinError(format!("theme: {}: no such theme", name)))
+}
+
+/// 所有可用主题的名字
+pub fn names() -> Vec<String> {
+    let mut names: Vec<String> = BUILTIN_THEMES.iter().map(|data| data.0.to_string()).collect();
+    if let Ok(entries) = fs::read_dir(themes_dir()) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "json")
+                && let Some(stem) = path.file_stem().and_then(|s| s.to_str())
+            {
+                names.push(stem.to_string());
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+// 当前主题，启动时由PSH_THEME选择
+static CURRENT: LazyLock<RwLock<Theme>> = LazyLock::new(|| {
+    let name = env::var("PSH_THEME").unwrap_or_else(|_| DEFAULT_THEME.to_string());
+    let theme = load(&name).unwrap_or_else(|e| {
+        error::report(e);
+        builtin(DEFAULT_THEME).unwrap()
+    });
+    RwLock::new(theme)
+});
+
+pub fn with_current<R>(f: impl FnOnce(&Theme) -> R) -> R {
+    f(&CURRENT.read().unwrap())
+}
+
+pub fn set_current(theme: Theme) {
+    *CURRENT.write().unwrap() = theme;
+}
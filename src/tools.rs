@@ -0,0 +1,213 @@
+use std::env;
+use std::io::{self, IsTerminal};
+use std::time::Duration;
+use serde_json::{json, Value};
+
+use crate::builtins;
+use crate::chat;
+use crate::config;
+use crate::error::ShellError;
+use crate::last_error;
+use crate::model_call::{llm_call_tools, Params};
+use crate::parser::{parse_line, Command};
+use crate::provider::{LlmProvider, Tool, ToolCall};
+use crate::run;
+
+// 最多进行几轮工具调用，可以用PSH_CHAT_TOOL_ROUNDS设置
+const DEFAULT_MAX_ROUNDS: usize = 5;
+// 返回给模型的命令输出的最大字符数，可以用PSH_CHAT_TOOL_OUTPUT设置
+const DEFAULT_OUTPUT_LIMIT: usize = 8000;
+// 命令最多执行的秒数，超时后结束命令，可以用PSH_CHAT_TOOL_TIMEOUT设置
+const DEFAULT_TIMEOUT_SECS: usize = 30;
+
+// 不需要确认就可以执行的只读命令，多个单词时要求参数以它们开头
+// 配置文件中的PSH_CHAT_TOOL_ALLOW（逗号分隔）会替换这份列表，设置为空则每条命令都需要确认
+// 这个设置只从配置文件读取，环境变量中的值会被忽略，避免其他程序把命令加入列表
+const DEFAULT_ALLOWLIST: &[&str] = &[
+    "pwd", "ls", "cat", "head", "tail", "wc", "grep", "file", "stat", "which", "du", "df", "uname", "echo",
+    "git status", "git log", "git diff", "git show",
+];
+
+// 会把输出写到文件的参数，带有这些参数的命令总是需要确认，如 git diff --output=FILE
+const WRITE_FLAGS: &[&str] = &["-o", "--output"];
+
+const RUN_COMMAND: &str = "run_command";
+
+fn run_command_tool() -> Tool {
+    Tool {
+        name: RUN_COMMAND,
+        description: "Run a command line in the user's psh shell, in the current directory, \
+                      and return its exit status, standard output and standard error."
+            .to_string(),
+        parameters: json!({
+            "type": "object",
+            "properties": {
+                "command": { "type": "string", "description": "The psh command line to run" },
+            },
+            "required": ["command"],
+        }),
+    }
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    env::var(name).ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+// 带工具的对话：模型请求执行命令时执行并把结果发回去，直到模型给出回答
+// 超过PSH_CHAT_TOOL_ROUNDS轮后不再执行命令，要求模型直接回答
+// 中间的工具调用不会保存到会话中，只返回最后的回答
+pub fn converse(provider: &dyn LlmProvider, params: &Params, mut messages: Vec<Value>, on_delta: &mut dyn FnMut(&str) -> Result<(), ShellError>) -> Result<String, ShellError> {
+    let tools = [run_command_tool()];
+    let max_rounds = env_usize("PSH_CHAT_TOOL_ROUNDS", DEFAULT_MAX_ROUNDS);
+
+    let mut round = 0;
+    loop {
+        let reply = llm_call_tools(provider, params, &messages, &tools)?;
+        if reply.tool_calls.is_empty() {
+            on_delta(&reply.text)?;
+            return Ok(reply.text);
+        }
+        // 已经告诉模型达到上限后仍然请求执行命令
+        if round > max_rounds {
+            return Err(ShellError::LLMError(format!("stopped after {} rounds of tool calls without an answer", max_rounds)));
+        }
+        round += 1;
+
+        let results: Vec<(ToolCall, String)> = reply.tool_calls.into_iter()
+            .map(|call| {
+                // 达到上限后不再执行，告诉模型用已有的信息回答
+                let output = if round > max_rounds {
+                    format!("Not run: the limit of {} rounds of commands was reached. Answer with the information you have.", max_rounds)
+                } else {
+                    run_tool(&call)
+                };
+                (call, output)
+            })
+            .collect();
+        messages.push(reply.message);
+        messages.extend(provider.tool_results(&results));
+    }
+}
+
+// 执行一次工具调用，返回交给模型的结果，失败和拒绝也作为结果告诉模型
+fn run_tool(call: &ToolCall) -> String {
+    if call.name != RUN_COMMAND {
+        return format!("error: unknown tool '{}'", call.name);
+    }
+    let Some(command) = call.arguments["command"].as_str() else {
+        return "error: missing 'command' argument".to_string();
+    };
+
+    match approve(command) {
+        Ok(true) => {}
+        Ok(false) => return "The user declined to run this command.".to_string(),
+        Err(e) => return format!("error: {}", e),
+    }
+
+    let timeout = env_usize("PSH_CHAT_TOOL_TIMEOUT", DEFAULT_TIMEOUT_SECS);
+    let (result, stderr) = last_error::capture_stderr(|| run::capture_command_limited(command, Duration::from_secs(timeout as u64)));
+    let (stdout, status, timed_out) = match result {
+        Ok(result) => result,
+        Err(e) => return format!("error: {}", e),
+    };
+
+    let limit = env_usize("PSH_CHAT_TOOL_OUTPUT", DEFAULT_OUTPUT_LIMIT);
+    let mut output = format!("exit status: {}\n<stdout>\n{}\n</stdout>", status, chat::truncate_middle(stdout.trim_end(), limit));
+    if timed_out {
+        eprintln!("  (killed after {} seconds)", timeout);
+        output = format!("The command was killed after running for {} seconds.\n{}", timeout, output);
+    }
+    if !stderr.trim().is_empty() {
+        output.push_str(&format!("\n<stderr>\n{}\n</stderr>", chat::truncate_middle(stderr.trim_end(), limit)));
+    }
+    output
+}
+
+// 只读命令直接执行，其他命令在终端上询问用户，不是终端时一律拒绝
+fn approve(command: &str) -> Result<bool, ShellError> {
+    let parsed = parse_line(command)?;
+    if matches!(parsed, Command::Exit) || contains_background(&parsed) {
+        return Err(ShellError::BuiltinError("exit and background jobs are not allowed".to_string()));
+    }
+
+    if is_read_only(&parsed) {
+        eprintln!("  $ {}", command);
+        return Ok(true);
+    }
+    if !io::stdin().is_terminal() {
+        eprintln!("  $ {}  (skipped, needs approval)", command);
+        return Ok(false);
+    }
+
+    eprintln!("  $ {}", command);
+    let answer = builtins::read_answer("Run this command? [y/N] ")?;
+    Ok(answer.is_some_and(|answer| matches!(answer.as_str(), "y" | "yes")))
+}
+
+fn contains_background(command: &Command) -> bool {
+    match command {
+        Command::Background(_) => true,
+        Command::Pipe(former, latter) => contains_background(former) || contains_background(latter),
+        _ => false,
+    }
+}
+
+// 管道中的每个命令都在允许列表中，并且没有输出重定向
+fn is_read_only(command: &Command) -> bool {
+    match command {
+        Command::Empty => true,
+        Command::Builtin(name, args, redirection) => redirection.output_file.is_none() && is_allowed(name, args),
+        Command::External(name, args) => !args.iter().any(|arg| arg == ">") && is_allowed(name, args),
+        Command::Pipe(former, latter) => is_read_only(former) && is_read_only(latter),
+        _ => false,
+    }
+}
+
+fn is_allowed(name: &str, args: &[String]) -> bool {
+    let writes_file = args.iter().any(|arg| {
+        WRITE_FLAGS.iter().any(|flag| arg == flag || arg.strip_prefix(flag).is_some_and(|rest| rest.starts_with('=')))
+    });
+    !writes_file && allowlist().iter().any(|entry| {
+        let mut words = entry.split_whitespace();
+        words.next() == Some(name)
+            && words.enumerate().all(|(i, word)| args.get(i).is_some_and(|arg| arg == word))
+    })
+}
+
+fn allowlist() -> Vec<String> {
+    match config::file_value("PSH_CHAT_TOOL_ALLOW") {
+        Some(list) => list.split(',')
+            .map(|entry| entry.trim().to_string())
+            .filter(|entry| !entry.is_empty())
+            .collect(),
+        None => DEFAULT_ALLOWLIST.iter().map(|entry| entry.to_string()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_only(line: &str) -> bool {
+        is_read_only(&parse_line(line).unwrap())
+    }
+
+    #[test]
+    fn allows_read_only_commands() {
+        for line in ["ls -la", "cat a.txt | grep x | wc -l", "git status", "git log --oneline -5", "git diff HEAD~1", "echo hi"] {
+            assert!(read_only(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn requires_approval_for_other_commands() {
+        for line in [
+            "rm -rf x", "git branch", "git branch -D main", "git branch newname", "git commit -m x",
+            "git diff --output=~/.bashrc", "git log --output FILE", "git show -o x", "echo hi > out", "ls | rm x",
+        ] {
+            assert!(!read_only(line), "{}", line);
+        }
+    }
+}